@@ -0,0 +1,14 @@
+// SM: 5_0
+
+uint4 PSMain(float4 position : SV_Position, nointerpolation uint4 bits : TEXCOORD0) : SV_Target
+{
+    uint4 result;
+    result.x = countbits(bits.x) + firstbithigh(bits.y) + firstbitlow(bits.z);
+    result.y = reversebits(bits.w) ^ firstbithigh(int(bits.x));
+    // Unpack a 10:10:10:2 normal and repack it the other way around
+    uint3 fields = (bits.xxx >> uint3(0, 10, 20)) & 0x3FF;
+    int signed_field = (int(bits.y) << 12) >> 22;
+    result.z = (fields.z) | (fields.y << 10) | (fields.x << 20) | (bits.x & 0xC0000000);
+    result.w = (bits.w & ~0xFF00) | ((asuint(signed_field) << 8) & 0xFF00);
+    return result;
+}
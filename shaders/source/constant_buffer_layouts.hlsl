@@ -0,0 +1,27 @@
+// SM: 4_1, 5_0
+
+struct Light
+{
+    float3 position;
+    float range;
+    float4 color;
+};
+
+cbuffer Lighting
+{
+    float4 sh[3];
+    float2 scales[2];
+    Light lights[2];
+    row_major float4x3 world;
+    float4x3 view;
+};
+
+float4 VSMain(float4 pos : POSITION) : SV_Position
+{
+    float3 world_pos = mul(pos, world);
+    float3 view_pos = mul(pos, view);
+    return sh[0] + sh[1] + sh[2]
+        + float4(scales[0], scales[1])
+        + float4(lights[0].position * lights[0].range, 1) * lights[1].color
+        + float4(world_pos + view_pos, 0);
+}
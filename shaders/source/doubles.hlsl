@@ -0,0 +1,29 @@
+// SM: 5_0
+
+cbuffer Precise
+{
+    double2 origin;
+    double scale;
+};
+
+float4 PSMain(float4 position : SV_Position) : SV_Target
+{
+    double2 p = double2(position.xy) * scale + origin;
+    double m = max(p.x, p.y);
+    double n = min(p.x, p.y) + 0.5;
+    float4 color = float4(float(m), float(n), 0, 1);
+    if (p.x == p.y || p.x < n || m >= n || p.y != m)
+    {
+        color.z = 1;
+    }
+    // Registers hold doubles on one side and floats on the other
+    if (position.z > 0.5)
+    {
+        color.w = float(p.x * p.y);
+    }
+    else
+    {
+        color.w = position.w * 2;
+    }
+    return color;
+}
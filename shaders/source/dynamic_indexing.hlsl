@@ -0,0 +1,36 @@
+// SM: 4_1, 5_0
+
+cbuffer Skinning
+{
+    float4 tint;
+    float4x3 bones[32];
+    float4 weights[4];
+};
+
+struct vs_input {
+    float4 position : POSITION;
+    uint4 indices : BLENDINDICES;
+};
+
+struct ps_input {
+    float4 position : SV_Position;
+    nointerpolation uint layer : TEXCOORD0;
+    float4 colors[3] : COLOR;
+};
+
+ps_input VSMain(const vs_input input)
+{
+    ps_input output;
+    float3 pos = mul(input.position, bones[input.indices.x]) * weights[input.indices.y].x;
+    output.position = float4(pos, 1) * tint;
+    output.layer = input.indices.z;
+    output.colors[0] = tint;
+    output.colors[1] = weights[0];
+    output.colors[2] = weights[1];
+    return output;
+}
+
+float4 PSMain(const ps_input input) : SV_Target
+{
+    return input.colors[input.layer % 3];
+}
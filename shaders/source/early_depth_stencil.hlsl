@@ -0,0 +1,7 @@
+// SM: 5_0
+
+[earlydepthstencil]
+float4 PSMain(float4 position : SV_Position) : SV_Target
+{
+    return position;
+}
@@ -0,0 +1,15 @@
+// SM: 4_1, 5_0
+
+static const float4 colors[4] = {
+    float4(1, 0, 0, 1),
+    float4(0, 1, 0, 1),
+    float4(0, 0, 1, 1),
+    float4(-0.5, 0.25, 1e-20, 3.4e38),
+};
+
+static const int offsets[3] = { -1, 0, 1 };
+
+float4 PSMain(float4 position : SV_Position, nointerpolation uint index : TEXCOORD0) : SV_Target
+{
+    return colors[index % 4] + offsets[index % 3];
+}
@@ -0,0 +1,12 @@
+// SM: 4_1, 5_0
+
+float4 PSMain(float4 position : SV_Position, nointerpolation uint index : TEXCOORD0) : SV_Target
+{
+    float4 offsets[4];
+    offsets[0] = float4(1, 0, 0, 1);
+    offsets[1] = float4(0, 1, 0, 1);
+    offsets[2] = float4(0, 0, 1, 1);
+    offsets[3] = position;
+    offsets[index % 2] += position;
+    return offsets[index % 4] + offsets[3];
+}
@@ -0,0 +1,10 @@
+// SM: 5_0
+
+uint4 PSMain(float4 position : SV_Position, nointerpolation uint4 values : TEXCOORD0) : SV_Target
+{
+    int signed_product = int(values.x) * int(values.y);
+    uint unsigned_product = values.z * values.w;
+    uint quotient = values.z / values.w;
+    uint remainder = values.z % values.w;
+    return uint4(asuint(signed_product), unsigned_product, quotient, remainder);
+}
@@ -0,0 +1,13 @@
+// SM: 4_1, 5_0
+
+struct ps_input {
+    float4 position : SV_Position;
+    nointerpolation uint material : MATERIAL;
+    noperspective float2 screen_uv : TEXCOORD0;
+    centroid float4 color : COLOR;
+};
+
+float4 PSMain(const ps_input input) : SV_Target
+{
+    return input.color * float4(input.screen_uv, input.material, 1);
+}
@@ -0,0 +1,26 @@
+// SM: 4_1, 5_0
+
+struct vs_input {
+    float4 position : POSITION;
+    float4 uvs : TEXCOORD0;
+};
+
+struct vs_output {
+    float4 position : SV_Position;
+    float2 uv0 : TEXCOORD0;
+    float2 uv1 : TEXCOORD1;
+};
+
+vs_output VSMain(const vs_input input)
+{
+    vs_output output;
+    output.position = input.position;
+    output.uv0 = input.uvs.zw;
+    output.uv1 = input.uvs.xy;
+    return output;
+}
+
+float4 PSMain(const vs_output input) : SV_Target
+{
+    return float4(input.uv0 + input.uv1, input.uv0.x, input.uv1.y);
+}
@@ -0,0 +1,19 @@
+// SM: 4_1, 5_0
+
+struct ps_input {
+    float4 position : SV_Position;
+    float4 color : COLOR;
+};
+
+struct ps_output {
+    float4 color : SV_Target0;
+    float4 emission : SV_Target2;
+};
+
+ps_output PSMain(const ps_input input)
+{
+    ps_output output;
+    output.color = input.color;
+    output.emission = input.color * 2;
+    return output;
+}
@@ -0,0 +1,14 @@
+// SM: 5_0
+
+struct ps_input {
+    float4 position : SV_Position;
+    bool front_face : SV_IsFrontFace;
+    uint sample_index : SV_SampleIndex;
+    uint primitive_id : SV_PrimitiveID;
+};
+
+float4 PSMain(const ps_input input) : SV_Target
+{
+    float face = input.front_face ? 1 : -1;
+    return float4(face, input.sample_index, input.primitive_id, input.position.z);
+}
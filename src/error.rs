@@ -0,0 +1,50 @@
+use std::fmt;
+
+use dxbc::binary::State;
+use dxbc::dr::shex::OperandType;
+use dxbc::dr::SemanticName;
+use naga::valid::ValidationError;
+use naga::WithSpan;
+
+/// Errors that can happen while converting DXBC to `naga` IR.
+#[derive(Debug)]
+pub enum Error {
+    /// `dxbc` couldn't parse the shader.
+    Parse(State),
+    /// A system-value semantic has no `naga` equivalent.
+    UnsupportedSemantic(SemanticName),
+    /// An `else`, `endif` or `endloop` doesn't close the `if` or `loop`
+    /// it should, or one is never closed.
+    UnbalancedControlFlow,
+    /// An operand refers to a register that isn't declared, like a constant
+    /// buffer missing from RDEF.
+    UndeclaredRegister(String),
+    /// A register index computed at runtime points into a constant buffer
+    /// member that isn't an array or matrix, or picks an output register.
+    UnsupportedDynamicIndex,
+    /// A source operand's type can't be read as a value.
+    UnsupportedOperand(OperandType),
+    /// The converted module failed validation, e.g. because it needs
+    /// capabilities that weren't allowed.
+    Validation(WithSpan<ValidationError>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(state) => write!(f, "couldn't parse shader: {:?}", state),
+            Self::UnsupportedSemantic(semantic) => {
+                write!(f, "semantic {:?} has no naga equivalent", semantic)
+            }
+            Self::UnbalancedControlFlow => write!(f, "if or loop isn't closed properly"),
+            Self::UndeclaredRegister(register) => write!(f, "{} isn't declared", register),
+            Self::UnsupportedDynamicIndex => {
+                write!(f, "register can't be indexed dynamically")
+            }
+            Self::UnsupportedOperand(ty) => write!(f, "{:?} operands aren't supported", ty),
+            Self::Validation(error) => write!(f, "module failed validation: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
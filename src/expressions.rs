@@ -1,16 +1,127 @@
 use dxbc::dr::shex::{Immediate, OperandType};
-use dxbc::dr::{ComponentMask, ComponentSelectMode, OperandToken0};
+use dxbc::dr::{OperandToken0, TestBoolean};
 use naga::proc::ResolveContext;
+use naga::valid::Capabilities;
 use naga::{
-    Constant, ConstantInner, Expression, Handle, ScalarKind, Span, Statement, SwizzleComponent,
-    Type, TypeInner, VectorSize,
+    ArraySize, BinaryOperator, Block, Constant, ConstantInner, Expression, Handle, MathFunction,
+    Module, ScalarKind, ScalarValue, Span, Statement, SwizzleComponent, Type, TypeInner,
+    UnaryOperator, VectorSize,
 };
 
 use crate::utils::{
-    get_component_name_index, get_first_immediate, get_immediate_value, get_immediate_width,
-    get_scalar_value, get_swizzle_component_index, get_swizzle_components, get_vector_size,
+    get_constant_buffer_slot, get_immediate_width, get_scalar_kind_and_size, get_scalar_value,
+    get_selected_components, get_selected_doubles, get_swizzle_component_from_index,
+    get_vector_size,
 };
-use crate::NagaConsumer;
+use crate::{Error, NagaConsumer};
+
+/// Where a single component of a DXBC register lives in the `naga` IR.
+///
+/// Registers aren't always backed by a single vector: several signature
+/// elements can be packed into one input or output register, e.g. two
+/// `float2`s in `v1.xy` and `v1.zw`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Component {
+    /// Pointer to, or value of, the scalar or vector holding the component.
+    pub base: Handle<Expression>,
+    /// Index of the component in `base`, or [`None`] if `base` is a scalar.
+    pub index: Option<u32>,
+}
+
+/// Components of a DXBC register. Components nothing is packed into are
+/// [`None`].
+pub(crate) type Register = [Option<Component>; 4];
+
+/// Index of a register, which may be computed at runtime, e.g. `cb0[r0.x + 12]`.
+pub(crate) enum RegisterIndex {
+    /// Index known ahead of time.
+    Static(u32),
+    /// Index computed by adding `offset` to the `u32` value of `index`.
+    Dynamic {
+        offset: u32,
+        index: Handle<Expression>,
+    },
+}
+
+/// One [`AccessIndex`][Expression::AccessIndex] on the way from a constant
+/// buffer to one of its scalars or vectors.
+#[derive(Clone, Copy, PartialEq)]
+struct PathStep {
+    /// Index of the member, element or column.
+    index: u32,
+    /// Registers taken up by each element or column, or [`None`] for struct
+    /// members.
+    registers: Option<u32>,
+}
+
+/// Find the [`PathStep`]s leading from a constant buffer to the scalar or
+/// vector containing a byte offset, along with the index of the component in
+/// that vector.
+///
+/// Returns [`None`] if the offset is padding.
+fn get_constant_buffer_path(
+    module: &Module,
+    ty: Handle<Type>,
+    offset: u32,
+) -> Option<(Vec<PathStep>, Option<u32>)> {
+    match module.types[ty].inner {
+        TypeInner::Scalar { width, .. } => (offset < width as u32).then(|| (Vec::new(), None)),
+        TypeInner::Vector { size, width, .. } => {
+            let index = offset / width as u32;
+            (index < size as u32).then(|| (Vec::new(), Some(index)))
+        }
+        TypeInner::Matrix {
+            columns,
+            rows,
+            width,
+        } => {
+            // Every column starts in a new register
+            let step = PathStep {
+                index: offset / 16,
+                registers: Some(1),
+            };
+            let index = offset % 16 / width as u32;
+            (step.index < columns as u32 && index < rows as u32).then(|| (vec![step], Some(index)))
+        }
+        TypeInner::Array {
+            base,
+            size: ArraySize::Constant(size),
+            stride,
+        } => {
+            let step = PathStep {
+                index: offset / stride,
+                registers: Some(stride / 16),
+            };
+            // Panic safety: array sizes are always integer constants
+            let size = module.constants[size].to_array_length().unwrap();
+            if step.index >= size {
+                return None;
+            }
+            let (mut path, index) = get_constant_buffer_path(module, base, offset % stride)?;
+            path.insert(0, step);
+            Some((path, index))
+        }
+        TypeInner::Struct { ref members, span } => {
+            if offset >= span {
+                return None;
+            }
+            let (i, member) = members
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, m)| m.offset <= offset)?;
+            let (mut path, index) =
+                get_constant_buffer_path(module, member.ty, offset - member.offset)?;
+            let step = PathStep {
+                index: i as u32,
+                registers: None,
+            };
+            path.insert(0, step);
+            Some((path, index))
+        }
+        _ => None,
+    }
+}
 
 /// Broad type of a type - scalar, vector, or pointer.
 ///
@@ -28,8 +139,31 @@ enum BroadType {
 }
 
 impl NagaConsumer {
-    /// Resolve [BroadType] corresponding to given [Expression].
-    fn get_broad_type(&mut self, expr: Handle<Expression>) -> BroadType {
+    /// Emit the expressions appended since the last emit, so statements
+    /// after this point can use them.
+    pub(crate) fn emit_expressions(&mut self) {
+        let len = self.function.expressions.len();
+        if len > self.emitted {
+            let range = self.function.expressions.range_from(self.emitted);
+            self.function
+                .body
+                .push(Statement::Emit(range), Span::UNDEFINED);
+        }
+        self.emitted = len;
+    }
+
+    /// Append an expression that's in scope without being emitted, such as a
+    /// constant, variable or function argument. Emit ranges can't include
+    /// these, so the expressions before it are emitted first.
+    pub(crate) fn append_unemitted(&mut self, expr: Expression, span: Span) -> Handle<Expression> {
+        self.emit_expressions();
+        let handle = self.function.expressions.append(expr, span);
+        self.emitted += 1;
+        handle
+    }
+
+    /// Resolve the type of an [Expression].
+    fn get_type(&mut self, expr: Handle<Expression>) -> TypeInner {
         let ctx = ResolveContext {
             constants: &self.module.constants,
             types: &self.module.types,
@@ -43,9 +177,35 @@ impl NagaConsumer {
         self.typifier
             .grow(expr, &self.function.expressions, &ctx)
             .unwrap();
-        let ty = self.typifier.get(expr, &self.module.types);
+        self.typifier.get(expr, &self.module.types).clone()
+    }
+
+    /// Resolve the type of the value of an [Expression], looking through
+    /// pointers.
+    pub(crate) fn get_value_type(&mut self, expr: Handle<Expression>) -> TypeInner {
+        match self.get_type(expr) {
+            TypeInner::Pointer { base, .. } => self.module.types[base].inner.clone(),
+            TypeInner::ValuePointer {
+                size: Some(size),
+                kind,
+                width,
+                ..
+            } => TypeInner::Vector { size, kind, width },
+            TypeInner::ValuePointer {
+                size: None,
+                kind,
+                width,
+                ..
+            } => TypeInner::Scalar { kind, width },
+            ty => ty,
+        }
+    }
 
-        if let TypeInner::Pointer { .. } = ty {
+    /// Resolve [BroadType] corresponding to given [Expression].
+    fn get_broad_type(&mut self, expr: Handle<Expression>) -> BroadType {
+        let ty = self.get_type(expr);
+
+        if let TypeInner::Pointer { .. } | TypeInner::ValuePointer { .. } = ty {
             BroadType::Pointer
         } else if ty.indexable_length(&self.module).is_ok() {
             BroadType::Vector
@@ -54,210 +214,595 @@ impl NagaConsumer {
         }
     }
 
-    /// [`Expression::Swizzle`] or [`AccessIndex`][Expression::AccessIndex] the
-    /// given expression, [`Load`][Expression::Load]ing beforehand as
-    /// necessary.
-    // TODO: pare down swizzles to human-like forms
-    fn get_swizzle(
+    /// Create an [`Expression::Constant`] of a [`ScalarKind`] for an immediate
+    /// operand and return its handle.
+    ///
+    /// Literals are stored as raw bits, so their kind comes from the
+    /// instruction using them.
+    fn get_immediate_expression(
         &mut self,
-        expr: Handle<Expression>,
         op: &OperandToken0,
+        kind: ScalarKind,
         span: Span,
     ) -> Handle<Expression> {
-        // If swizzle target is a scalar, just return the target
-        // If it's a vector, it's fine
-        // If it's a pointer, load it and check again
-        // TODO: this is bad and doesn't deal with matrices
-        let broad_ty = self.get_broad_type(expr);
-        let vector = match broad_ty {
-            BroadType::Scalar => return expr,
-            BroadType::Vector => expr,
-            BroadType::Pointer => {
-                let load_expr = Expression::Load { pointer: expr };
-                let load_expr = self.function.expressions.append(load_expr, span);
-                let loaded_ty = self.get_broad_type(load_expr);
-                match loaded_ty {
-                    BroadType::Scalar => return load_expr,
-                    BroadType::Vector => load_expr,
-                    // I'm not dealing with pointers to pointers
-                    BroadType::Pointer => unimplemented!(),
+        let imms = op.get_immediates();
+        // Panic safety: immediate operands have at least one value
+        let width = get_immediate_width(imms.first().unwrap());
+        let mut components: Vec<Handle<Constant>> = imms
+            .iter()
+            .map(|imm| {
+                let c = Constant {
+                    name: None,
+                    // TODO: find out what this is
+                    specialization: None,
+                    inner: ConstantInner::Scalar {
+                        width,
+                        value: get_scalar_value(imm, kind),
+                    },
+                };
+                self.module.constants.fetch_or_append(c, span)
+            })
+            .collect();
+
+        let c = if components.len() == 1 {
+            // Panic safety: there's exactly one component
+            components.pop().unwrap()
+        } else {
+            let ty = Type {
+                name: None,
+                inner: TypeInner::Vector {
+                    size: get_vector_size(components.len()),
+                    kind,
+                    width,
+                },
+            };
+            let ty = self.module.types.insert(ty, span);
+            let c = Constant {
+                name: None,
+                specialization: None,
+                inner: ConstantInner::Composite { ty, components },
+            };
+            self.module.constants.fetch_or_append(c, span)
+        };
+        self.append_unemitted(Expression::Constant(c), span)
+    }
+
+    /// Get an [`Expression::Constant`] for a 32-bit scalar.
+    pub(crate) fn get_scalar_constant(&mut self, value: ScalarValue) -> Handle<Expression> {
+        let c = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Scalar { width: 4, value },
+        };
+        let c = self.module.constants.fetch_or_append(c, Span::UNDEFINED);
+        self.append_unemitted(Expression::Constant(c), Span::UNDEFINED)
+    }
+
+    /// Get an [`Expression::Constant`] for a 32-bit scalar, splatted to the
+    /// size of another value if that's a vector.
+    pub(crate) fn get_constant_like(
+        &mut self,
+        value: ScalarValue,
+        like: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let c = self.get_scalar_constant(value);
+        let (_, size) = get_scalar_kind_and_size(&self.get_value_type(like));
+        if size == 1 {
+            return c;
+        }
+        let splat = Expression::Splat {
+            size: get_vector_size(size),
+            value: c,
+        };
+        self.function.expressions.append(splat, span)
+    }
+
+    /// Get a zero [Constant] of a scalar or vector type.
+    pub(crate) fn get_zero_constant(&mut self, ty: Handle<Type>) -> Handle<Constant> {
+        let (kind, size, width) = match self.module.types[ty].inner {
+            TypeInner::Scalar { kind, width } => (kind, 1, width),
+            TypeInner::Vector { size, kind, width } => (kind, size as usize, width),
+            _ => unimplemented!(),
+        };
+        let value = match kind {
+            ScalarKind::Sint => ScalarValue::Sint(0),
+            ScalarKind::Uint => ScalarValue::Uint(0),
+            ScalarKind::Float => ScalarValue::Float(0.0),
+            ScalarKind::Bool => ScalarValue::Bool(false),
+        };
+        let scalar = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Scalar { width, value },
+        };
+        let scalar = self
+            .module
+            .constants
+            .fetch_or_append(scalar, Span::UNDEFINED);
+        if size == 1 {
+            return scalar;
+        }
+
+        let c = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Composite {
+                ty,
+                components: vec![scalar; size],
+            },
+        };
+        self.module.constants.fetch_or_append(c, Span::UNDEFINED)
+    }
+
+    /// Get a boolean expression that's true if the selected component of an
+    /// operand passes a [`TestBoolean`] test.
+    pub(crate) fn get_test_condition(
+        &mut self,
+        op: &OperandToken0,
+        test: TestBoolean,
+        span: Span,
+    ) -> Handle<Expression> {
+        // The test looks at the raw bits, so -0.0 is nonzero
+        let value = self.get_src_variable_expression(op, ScalarKind::Uint, span);
+        let zero = self.get_scalar_constant(ScalarValue::Uint(0));
+        let op = match test {
+            TestBoolean::Zero => BinaryOperator::Equal,
+            TestBoolean::NonZero => BinaryOperator::NotEqual,
+        };
+        let expr = Expression::Binary {
+            op,
+            left: value,
+            right: zero,
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Get the [Register] an input, output, temporary, indexable temporary,
+    /// constant buffer or immediate constant buffer operand refers to.
+    fn get_register(&mut self, op: &OperandToken0, span: Span) -> Register {
+        if let OperandType::ImmediateConstantBuffer = op.get_operand_type() {
+            let icb = match self.immediate_constant_buffer {
+                Some(icb) => icb,
+                None => {
+                    self.fail(Error::UndeclaredRegister("icb".to_owned()));
+                    return Register::default();
                 }
+            };
+            let icb = Expression::GlobalVariable(icb);
+            let icb = self.append_unemitted(icb, span);
+            return self.get_element_register(icb, &op.get_immediate(0), span);
+        }
+
+        // Inputs read at a dynamic register select between every input
+        // before getting here, and nothing else can be indexed like that
+        let i = match self.get_register_index(&op.get_immediate(0), span) {
+            RegisterIndex::Static(i) => i,
+            RegisterIndex::Dynamic { .. } => {
+                self.fail(Error::UnsupportedDynamicIndex);
+                return Register::default();
             }
         };
-
-        // 1-component swizzles are just accesses: https://github.com/gfx-rs/naga/blob/cf32c2b7f38c985e1c770eeff05a91e0cd15ee04/src/front/glsl/variables.rs#L343
-        let mode = op.get_component_select_mode();
-        let swizzle = if let ComponentSelectMode::Select1 = mode {
-            Expression::AccessIndex {
-                base: vector,
-                index: get_component_name_index(op.get_component_swizzle().0),
+        match op.get_operand_type() {
+            OperandType::ConstantBuffer => {
+                let slot = self.get_register_index(&get_constant_buffer_slot(op), span);
+                self.get_constant_buffer_register(i, slot, span)
             }
-        } else {
-            let (size, pattern): (VectorSize, [SwizzleComponent; 4]) =
-                match op.get_component_select_mode() {
-                    ComponentSelectMode::Mask => {
-                        let mut components = Vec::with_capacity(4);
-                        let mask = op.get_component_mask();
+            OperandType::Temp => {
+                let base = self.temps[i as usize];
+                [0, 1, 2, 3].map(|index| {
+                    Some(Component {
+                        base,
+                        index: Some(index),
+                    })
+                })
+            }
+            OperandType::IndexableTemp => {
+                let array = match self.indexable_temps.get(&i) {
+                    Some(array) => *array,
+                    None => {
+                        self.fail(Error::UndeclaredRegister(format!("x{}", i)));
+                        return Register::default();
+                    }
+                };
+                self.get_element_register(array, &op.get_immediate(1), span)
+            }
+            OperandType::Input => self.input_registers.get(&i).copied().unwrap_or_default(),
+            OperandType::Output => self.output_registers.get(&i).copied().unwrap_or_default(),
+            _ => unreachable!(),
+        }
+    }
 
-                        if mask.bits().count_ones() == 1 {}
+    /// Get the [Register] of an element of a pointer to an array of scalars
+    /// or vectors.
+    fn get_element_register(
+        &mut self,
+        array: Handle<Expression>,
+        imm: &Immediate,
+        span: Span,
+    ) -> Register {
+        let expr = match self.get_register_index(imm, span) {
+            RegisterIndex::Static(index) => Expression::AccessIndex { base: array, index },
+            RegisterIndex::Dynamic { offset, index } => Expression::Access {
+                base: array,
+                index: self.get_offset_index(offset, index, span),
+            },
+        };
+        let base = self.function.expressions.append(expr, span);
 
-                        if mask.contains(ComponentMask::COMPONENT_MASK_R) {
-                            components.push(SwizzleComponent::X);
-                        }
-                        if mask.contains(ComponentMask::COMPONENT_MASK_G) {
-                            components.push(SwizzleComponent::Y);
-                        }
-                        if mask.contains(ComponentMask::COMPONENT_MASK_B) {
-                            components.push(SwizzleComponent::Z);
-                        }
-                        if mask.contains(ComponentMask::COMPONENT_MASK_A) {
-                            components.push(SwizzleComponent::W);
-                        }
+        // Components past the size of the elements don't exist
+        let (_, size) = get_scalar_kind_and_size(&self.get_value_type(base));
+        let mut register = Register::default();
+        for (c, component) in register.iter_mut().take(size).enumerate() {
+            let index = (size > 1).then(|| c as u32);
+            *component = Some(Component { base, index });
+        }
+        register
+    }
 
-                        if components.len() == 1 {
-                            let expr = Expression::AccessIndex {
-                                base: vector,
-                                // Panic safety: components.len() == 1
-                                index: get_swizzle_component_index(components.first().unwrap()),
-                            };
-                            return self.function.expressions.append(expr, span);
-                        }
+    /// Get the [RegisterIndex] an immediate refers to, computing relative
+    /// indices from their register.
+    pub(crate) fn get_register_index(&mut self, imm: &Immediate, span: Span) -> RegisterIndex {
+        let (offset, op) = match imm {
+            Immediate::U32(n) => return RegisterIndex::Static(*n),
+            Immediate::U64(n) => return RegisterIndex::Static(*n as u32),
+            Immediate::Relative(op) => (0, op),
+            Immediate::U32Relative(n, op) => (*n, op),
+            Immediate::U64Relative(n, op) => (*n as u32, op),
+        };
+        let index = self.get_src_variable_expression(op, ScalarKind::Uint, span);
+        RegisterIndex::Dynamic { offset, index }
+    }
 
-                        let size = get_vector_size(components.len());
-                        for _ in 0..4 - components.len() {
-                            components.push(SwizzleComponent::X);
-                        }
+    /// Add the offset of a [`RegisterIndex::Dynamic`] to its index.
+    pub(crate) fn get_offset_index(
+        &mut self,
+        offset: u32,
+        index: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        if offset == 0 {
+            return index;
+        }
+        let offset = self.get_scalar_constant(ScalarValue::Uint(offset as u64));
+        let expr = Expression::Binary {
+            op: BinaryOperator::Add,
+            left: index,
+            right: offset,
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Get the [Register] of a 16-byte slot of the constant buffer with ID
+    /// `id`, bound to `cb{id}` before shader model 5.1.
+    ///
+    /// Each component is resolved to the member, matrix column or scalar
+    /// that HLSL packed into it. Components that are only padding are
+    /// [`None`].
+    ///
+    /// Dynamic slots are resolved from their offset, with the index applied
+    /// to the outermost array or matrix there. fxc scales indices into arrays
+    /// by the registers each element takes up, so that's undone.
+    fn get_constant_buffer_register(
+        &mut self,
+        id: u32,
+        slot: RegisterIndex,
+        span: Span,
+    ) -> Register {
+        let global = match self.constant_buffers.get(&id) {
+            Some(global) => *global,
+            None => {
+                self.fail(Error::UndeclaredRegister(format!("cb{}", id)));
+                return Register::default();
+            }
+        };
+        let ty = self.module.global_variables[global].ty;
+        let (slot, dynamic) = match slot {
+            RegisterIndex::Static(slot) => (slot, None),
+            RegisterIndex::Dynamic { offset, index } => (offset, Some(index)),
+        };
+
+        let mut register = Register::default();
+        // Components of a register usually share a path, so only build each
+        // chain of accesses once
+        let mut bases: Vec<(Vec<PathStep>, Handle<Expression>)> = Vec::new();
+        for (c, component) in register.iter_mut().enumerate() {
+            let offset = slot * 16 + c as u32 * 4;
+            let (path, index) = match get_constant_buffer_path(&self.module, ty, offset) {
+                Some(path) => path,
+                None => continue,
+            };
 
-                        // Panic safety: we explicitly add dummy elements until the
-                        // array is of length 4 so this won't fail
-                        (size, components.try_into().unwrap())
+            let base = match bases.iter().find(|(p, _)| *p == path) {
+                Some((_, base)) => *base,
+                None => {
+                    let expr = Expression::GlobalVariable(global);
+                    let mut base = self.append_unemitted(expr, span);
+                    let mut dynamic = dynamic;
+                    for step in &path {
+                        let expr = match (dynamic, step.registers) {
+                            (Some(index), Some(registers)) => {
+                                dynamic = None;
+                                let index =
+                                    self.get_scaled_index(index, registers, step.index, span);
+                                Expression::Access { base, index }
+                            }
+                            _ => Expression::AccessIndex {
+                                base,
+                                index: step.index,
+                            },
+                        };
+                        base = self.function.expressions.append(expr, span);
                     }
-                    ComponentSelectMode::Swizzle => {
-                        let swizzle = op.get_component_swizzle();
-                        (VectorSize::Quad, get_swizzle_components(&swizzle))
+                    // fxc only indexes arrays and matrices
+                    if dynamic.is_some() {
+                        self.fail(Error::UnsupportedDynamicIndex);
                     }
-                    _ => unreachable!(),
-                };
+                    bases.push((path, base));
+                    base
+                }
+            };
+            *component = Some(Component { base, index });
+        }
+        register
+    }
 
-            Expression::Swizzle {
-                size,
-                vector,
-                pattern,
-            }
+    /// Get `index / registers + offset`, the index of an array element or
+    /// matrix column from a dynamic register index.
+    fn get_scaled_index(
+        &mut self,
+        index: Handle<Expression>,
+        registers: u32,
+        offset: u32,
+        span: Span,
+    ) -> Handle<Expression> {
+        let index = if registers == 1 {
+            index
+        } else {
+            let registers = self.get_scalar_constant(ScalarValue::Uint(registers as u64));
+            let expr = Expression::Binary {
+                op: BinaryOperator::Divide,
+                left: index,
+                right: registers,
+            };
+            self.function.expressions.append(expr, span)
         };
+        self.get_offset_index(offset, index, span)
+    }
+
+    /// Get the value of an input register indexed at runtime, e.g.
+    /// `v[r0.x + 1]`, by selecting between every input register.
+    fn get_indexed_input_value(
+        &mut self,
+        op: &OperandToken0,
+        offset: u32,
+        index: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let index = self.get_offset_index(offset, index, span);
+        let mut registers: Vec<(u32, Register)> =
+            self.input_registers.iter().map(|(i, r)| (*i, *r)).collect();
+        registers.sort_by_key(|(i, _)| *i);
 
-        self.function.expressions.append(swizzle, span)
+        let mut value: Option<Handle<Expression>> = None;
+        let mut kind = None;
+        for (i, register) in registers {
+            let accept = self.get_register_value(op, &register, span);
+            let accept = match kind {
+                Some(kind) => self.bitcast(accept, kind, span),
+                None => {
+                    kind = Some(get_scalar_kind_and_size(&self.get_value_type(accept)).0);
+                    accept
+                }
+            };
+            value = Some(match value {
+                None => accept,
+                Some(reject) => {
+                    let i = self.get_scalar_constant(ScalarValue::Uint(i as u64));
+                    let condition = Expression::Binary {
+                        op: BinaryOperator::Equal,
+                        left: index,
+                        right: i,
+                    };
+                    let condition = self.function.expressions.append(condition, span);
+                    let select = Expression::Select {
+                        condition,
+                        accept,
+                        reject,
+                    };
+                    self.function.expressions.append(select, span)
+                }
+            });
+        }
+        // Panic safety: shaders that index inputs have inputs
+        value.unwrap()
     }
 
-    /// Create an [Expression] corresponding to an operand and return its
-    /// handle.
-    fn get_variable_expression(&mut self, op: &OperandToken0, span: Span) -> Handle<Expression> {
-        let handle = match op.get_operand_type() {
-            OperandType::ConstantBuffer => {
-                let imms = op.get_immediates();
-                let cb_index = get_immediate_value(&imms[0]) as usize;
-                let var_index = get_immediate_value(&imms[1]) as usize;
-                let expr = self.constant_buffers[cb_index][var_index];
-                Some(expr)
+    /// [`Load`][Expression::Load] an expression if it's a pointer.
+    fn get_value(&mut self, expr: Handle<Expression>, span: Span) -> Handle<Expression> {
+        if let BroadType::Pointer = self.get_broad_type(expr) {
+            let load_expr = Expression::Load { pointer: expr };
+            self.function.expressions.append(load_expr, span)
+        } else {
+            expr
+        }
+    }
+
+    /// Reinterpret the bits of a value as another [`ScalarKind`] if it isn't
+    /// already of that kind.
+    ///
+    /// DXBC registers are untyped, so the same bits can be read as floats by
+    /// one instruction and integers by the next.
+    pub(crate) fn bitcast(
+        &mut self,
+        expr: Handle<Expression>,
+        kind: ScalarKind,
+        span: Span,
+    ) -> Handle<Expression> {
+        let (expr_kind, _) = get_scalar_kind_and_size(&self.get_value_type(expr));
+        if expr_kind == kind {
+            expr
+        } else if kind == ScalarKind::Bool {
+            // Bools are stored as `u32`s, with any nonzero bits being true
+            let expr = self.bitcast(expr, ScalarKind::Uint, span);
+            self.get_nonzero(expr, span)
+        } else {
+            let cast = Expression::As {
+                expr,
+                kind,
+                convert: None,
+            };
+            self.function.expressions.append(cast, span)
+        }
+    }
+
+    /// Get the value of several [Component]s as a scalar or vector.
+    ///
+    /// Components from the same base are swizzled out of it. Components from
+    /// different bases are composed into a new vector, bitcast to the kind of
+    /// the first component if necessary.
+    pub(crate) fn get_components_value(
+        &mut self,
+        components: &[Component],
+        span: Span,
+    ) -> Handle<Expression> {
+        let base = components[0].base;
+        if components.iter().all(|c| c.base == base) {
+            let value = self.get_value(base, span);
+
+            if components[0].index.is_none() {
+                return if components.len() == 1 {
+                    value
+                } else {
+                    let splat = Expression::Splat {
+                        size: get_vector_size(components.len()),
+                        value,
+                    };
+                    self.function.expressions.append(splat, span)
+                };
             }
-            OperandType::Temp => {
-                let i = get_first_immediate(*op);
-                Some(self.temps[i as usize])
+
+            // Panic safety: components with the same base are either all
+            // scalars or all vector components
+            let indices: Vec<u32> = components.iter().map(|c| c.index.unwrap()).collect();
+            if indices.len() == 1 {
+                let expr = Expression::AccessIndex {
+                    base: value,
+                    index: indices[0],
+                };
+                return self.function.expressions.append(expr, span);
             }
-            OperandType::Output => {
-                let i = get_first_immediate(*op);
-                Some(self.outs[i as usize])
+
+            let (_, size) = get_scalar_kind_and_size(&self.get_value_type(value));
+            if size == indices.len() && indices.iter().enumerate().all(|(i, c)| i as u32 == *c) {
+                return value;
             }
-            _ => {
-                let expr = match op.get_operand_type() {
-                    // TODO: collect inputs into ins
-                    OperandType::Input => {
-                        let index = get_first_immediate(*op);
-                        let base = Expression::FunctionArgument(0);
-                        let base = self.function.expressions.append(base, span);
-                        let member = Expression::AccessIndex { base, index };
-                        Some(member)
-                    }
-                    OperandType::Immediate32 => {
-                        let imms = op.get_immediates();
-                        let first = imms.first().unwrap();
-                        if imms.len() == 1 {
-                            let c = Constant {
-                                name: None,
-                                // TODO: find out what this is
-                                specialization: None,
-                                inner: ConstantInner::Scalar {
-                                    width: get_immediate_width(first),
-                                    value: get_scalar_value(first),
-                                },
-                            };
-                            let const_handle = self.module.constants.fetch_or_append(c, span);
-                            Some(Expression::Constant(const_handle))
-                        } else {
-                            let width = match first {
-                                Immediate::U32(_) => 4,
-                                Immediate::U64(_) => 8,
-                                Immediate::Relative(_) => todo!(),
-                                Immediate::U32Relative(_, _) => todo!(),
-                                Immediate::U64Relative(_, _) => todo!(),
-                            };
-                            let ty = Type {
-                                name: None,
-                                inner: TypeInner::Vector {
-                                    size: get_vector_size(imms.len()),
-                                    kind: match first {
-                                        Immediate::U32(_) | Immediate::U64(_) => ScalarKind::Uint,
-                                        Immediate::Relative(_) => todo!(),
-                                        Immediate::U32Relative(_, _) => todo!(),
-                                        Immediate::U64Relative(_, _) => todo!(),
-                                    },
-                                    width,
-                                },
-                            };
-                            let ty = self.module.types.insert(ty, span);
-                            let components: Vec<Handle<Constant>> = imms
-                                .into_iter()
-                                .map(|imm| {
-                                    let c = Constant {
-                                        name: None,
-                                        specialization: None,
-                                        inner: ConstantInner::Scalar {
-                                            width,
-                                            value: get_scalar_value(&imm),
-                                        },
-                                    };
-                                    self.module.constants.fetch_or_append(c, span)
-                                })
-                                .collect();
-                            let c = Constant {
-                                name: None,
-                                specialization: None,
-                                inner: ConstantInner::Composite { ty, components },
-                            };
-                            let handle = self.module.constants.fetch_or_append(c, span);
-                            Some(Expression::Constant(handle))
-                        }
-                    }
-                    _ => None,
-                };
 
-                if let Some(e) = expr {
-                    Some(self.function.expressions.append(e, span))
-                } else {
-                    None
-                }
+            let mut pattern = [SwizzleComponent::X; 4];
+            for (p, c) in pattern.iter_mut().zip(&indices) {
+                *p = get_swizzle_component_from_index(*c);
             }
+            let swizzle = Expression::Swizzle {
+                size: get_vector_size(indices.len()),
+                vector: value,
+                pattern,
+            };
+            return self.function.expressions.append(swizzle, span);
+        }
+
+        let mut kind = None;
+        let mut scalars = Vec::with_capacity(components.len());
+        for c in components {
+            let scalar = self.get_components_value(&[*c], span);
+            let scalar = match kind {
+                Some(kind) => self.bitcast(scalar, kind, span),
+                None => {
+                    kind = Some(get_scalar_kind_and_size(&self.get_value_type(scalar)).0);
+                    scalar
+                }
+            };
+            scalars.push(scalar);
+        }
+        self.get_composed_value(scalars, span)
+    }
+
+    /// Compose 32-bit scalars of the same kind into a vector, or get the
+    /// scalar if there's only one.
+    pub(crate) fn get_composed_value(
+        &mut self,
+        mut scalars: Vec<Handle<Expression>>,
+        span: Span,
+    ) -> Handle<Expression> {
+        if scalars.len() == 1 {
+            // Panic safety: there's exactly one scalar
+            return scalars.pop().unwrap();
+        }
+        let (kind, _) = get_scalar_kind_and_size(&self.get_value_type(scalars[0]));
+        let ty = Type {
+            name: None,
+            inner: TypeInner::Vector {
+                size: get_vector_size(scalars.len()),
+                kind,
+                width: 4,
+            },
         };
+        let ty = self.module.types.insert(ty, span);
+        let compose = Expression::Compose {
+            ty,
+            components: scalars,
+        };
+        self.function.expressions.append(compose, span)
+    }
 
-        if let Some(h) = handle {
-            if let Expression::Constant(_) = self.function.expressions[h] {
-                h
-            } else {
-                self.get_swizzle(h, op, span)
-            }
-        } else {
-            todo!()
+    /// Get the scalars of source values that go into each component a
+    /// destination writes.
+    ///
+    /// Some `naga` functions only take scalar arguments where DXBC has a
+    /// value per component, so those instructions work one component at a
+    /// time. Compose the results with
+    /// [`get_composed_value`][NagaConsumer::get_composed_value].
+    pub(crate) fn get_component_sources(
+        &mut self,
+        dst: &OperandToken0,
+        values: &[Handle<Expression>],
+        span: Span,
+    ) -> Vec<Vec<Handle<Expression>>> {
+        let mut sources = Vec::with_capacity(4);
+        for (position, c) in get_selected_components(dst).into_iter().enumerate() {
+            let scalars = values
+                .iter()
+                .map(|&value| {
+                    let component = self.get_value_component(value, c, position as u32);
+                    self.get_components_value(&[component], span)
+                })
+                .collect();
+            sources.push(scalars);
         }
+        sources
+    }
+
+    /// Get the [Component] of a computed value that should be written to a
+    /// destination component.
+    ///
+    /// `component` is the index of the destination component in its
+    /// register and `position` is its index among the written components.
+    fn get_value_component(
+        &mut self,
+        value: Handle<Expression>,
+        component: u32,
+        position: u32,
+    ) -> Component {
+        let index = match self.get_value_type(value) {
+            TypeInner::Scalar { .. } => None,
+            // Full-width values line up with the register
+            TypeInner::Vector {
+                size: VectorSize::Quad,
+                ..
+            } => Some(component),
+            // Narrower values only hold the written components
+            _ => Some(position),
+        };
+        Component { base: value, index }
     }
 
     pub(crate) fn get_dst_variable_statement(
@@ -266,21 +811,485 @@ impl NagaConsumer {
         span: Span,
         value: Handle<Expression>,
     ) -> Statement {
-        let pointer = self.get_variable_expression(op, span);
-        Statement::Store { pointer, value }
+        let register = self.get_register(op, span);
+
+        // Group written components by what they're packed into so each
+        // element is stored with as few statements as possible
+        let mut groups: Vec<(Handle<Expression>, Vec<(Option<u32>, Component)>)> = Vec::new();
+        for (position, c) in get_selected_components(op).into_iter().enumerate() {
+            // Writes to components nothing is packed into are dead
+            let dst = match register[c as usize] {
+                Some(dst) => dst,
+                None => continue,
+            };
+            let src = self.get_value_component(value, c, position as u32);
+            match groups.iter_mut().find(|(base, _)| *base == dst.base) {
+                Some((_, group)) => group.push((dst.index, src)),
+                None => groups.push((dst.base, vec![(dst.index, src)])),
+            }
+        }
+
+        let mut statements = Vec::with_capacity(groups.len());
+        for (base, group) in groups {
+            let (kind, size) = get_scalar_kind_and_size(&self.get_value_type(base));
+            let whole = group.len() == size
+                && group
+                    .iter()
+                    .enumerate()
+                    .all(|(i, (index, _))| index.map_or(true, |index| index == i as u32));
+
+            if whole {
+                let src: Vec<Component> = group.iter().map(|(_, src)| *src).collect();
+                let value = self.get_components_value(&src, span);
+                let value = self.bitcast(value, kind, span);
+                statements.push(Statement::Store {
+                    pointer: base,
+                    value,
+                });
+            } else {
+                for (index, src) in group {
+                    let pointer = match index {
+                        Some(index) => {
+                            let expr = Expression::AccessIndex { base, index };
+                            self.function.expressions.append(expr, span)
+                        }
+                        None => base,
+                    };
+                    let value = self.get_components_value(&[src], span);
+                    let value = self.bitcast(value, kind, span);
+                    statements.push(Statement::Store { pointer, value });
+                }
+            }
+        }
+
+        if statements.len() == 1 {
+            // Panic safety: there's exactly one statement
+            statements.pop().unwrap()
+        } else {
+            Statement::Block(Block::from_vec(statements))
+        }
+    }
+
+    /// Get the value of the components of a [Register] an operand selects.
+    fn get_register_value(
+        &mut self,
+        op: &OperandToken0,
+        register: &Register,
+        span: Span,
+    ) -> Handle<Expression> {
+        // Swizzles like .xyzx can read components nothing is packed into,
+        // whose value doesn't matter. Registers are only completely empty
+        // if they failed to resolve, and then nothing is converted anyway
+        let fallback = match register.iter().flatten().next() {
+            Some(component) => *component,
+            None => Component {
+                base: self.get_scalar_constant(ScalarValue::Float(0.0)),
+                index: None,
+            },
+        };
+        let components: Vec<Component> = get_selected_components(op)
+            .into_iter()
+            .map(|c| register[c as usize].unwrap_or(fallback))
+            .collect();
+        self.get_components_value(&components, span)
     }
 
+    /// Get the value of a source operand as a [`ScalarKind`].
     pub(crate) fn get_src_variable_expression(
         &mut self,
         op: &OperandToken0,
+        kind: ScalarKind,
         span: Span,
     ) -> Handle<Expression> {
-        let var_expr = self.get_variable_expression(op, span);
-        if let BroadType::Pointer = self.get_broad_type(var_expr) {
-            let load_expr = Expression::Load { pointer: var_expr };
-            self.function.expressions.append(load_expr, span)
-        } else {
-            var_expr
+        let value = match op.get_operand_type() {
+            OperandType::Immediate32 | OperandType::Immediate64 => {
+                return self.get_immediate_expression(op, kind, span);
+            }
+            OperandType::Input => match self.get_register_index(&op.get_immediate(0), span) {
+                RegisterIndex::Static(_) => {
+                    let register = self.get_register(op, span);
+                    self.get_register_value(op, &register, span)
+                }
+                RegisterIndex::Dynamic { offset, index } => {
+                    self.get_indexed_input_value(op, offset, index, span)
+                }
+            },
+            OperandType::Temp
+            | OperandType::IndexableTemp
+            | OperandType::Output
+            | OperandType::ConstantBuffer
+            | OperandType::ImmediateConstantBuffer => {
+                let register = self.get_register(op, span);
+                self.get_register_value(op, &register, span)
+            }
+            ty => {
+                self.fail(Error::UnsupportedOperand(ty));
+                self.get_scalar_constant(ScalarValue::Uint(0))
+            }
+        };
+        self.bitcast(value, kind, span)
+    }
+
+    /// Get an [`Expression::Constant`] for an `f64`.
+    fn get_double_constant(&mut self, value: f64) -> Handle<Expression> {
+        let c = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Scalar {
+                width: 8,
+                value: ScalarValue::Float(value),
+            },
+        };
+        let c = self.module.constants.fetch_or_append(c, Span::UNDEFINED);
+        self.append_unemitted(Expression::Constant(c), Span::UNDEFINED)
+    }
+
+    /// Append an [`Expression::Binary`].
+    pub(crate) fn append_binary(
+        &mut self,
+        op: BinaryOperator,
+        left: Handle<Expression>,
+        right: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let expr = Expression::Binary { op, left, right };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Append an [`Expression::Math`] with one or two arguments.
+    fn append_math(
+        &mut self,
+        fun: MathFunction,
+        arg: Handle<Expression>,
+        arg1: Option<Handle<Expression>>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let expr = Expression::Math {
+            fun,
+            arg,
+            arg1,
+            arg2: None,
+            arg3: None,
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Convert a scalar to another kind and width by value.
+    fn append_convert(
+        &mut self,
+        expr: Handle<Expression>,
+        kind: ScalarKind,
+        width: u8,
+        span: Span,
+    ) -> Handle<Expression> {
+        let expr = Expression::As {
+            expr,
+            kind,
+            convert: Some(width),
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Append an [`Expression::Select`].
+    fn append_select(
+        &mut self,
+        condition: Handle<Expression>,
+        accept: Handle<Expression>,
+        reject: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let expr = Expression::Select {
+            condition,
+            accept,
+            reject,
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Get the `f64` whose low and high bits are the `u32`s `lo` and `hi`.
+    ///
+    /// `naga` can't reinterpret a pair of `u32`s as an `f64`, so it's put
+    /// together from its sign, exponent and mantissa instead. NaNs come out
+    /// as infinities.
+    fn get_double_from_bits(
+        &mut self,
+        lo: Handle<Expression>,
+        hi: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let zero = self.get_scalar_constant(ScalarValue::Uint(0));
+        let shift = self.get_scalar_constant(ScalarValue::Uint(20));
+        let exponent = self.append_binary(BinaryOperator::ShiftRight, hi, shift, span);
+        let mask = self.get_scalar_constant(ScalarValue::Uint(0x7FF));
+        let exponent = self.append_binary(BinaryOperator::And, exponent, mask, span);
+
+        let mask = self.get_scalar_constant(ScalarValue::Uint(0xFFFFF));
+        let high = self.append_binary(BinaryOperator::And, hi, mask, span);
+        let high = self.append_convert(high, ScalarKind::Float, 8, span);
+        let scale = self.get_double_constant(4294967296.0);
+        let high = self.append_binary(BinaryOperator::Multiply, high, scale, span);
+        let low = self.append_convert(lo, ScalarKind::Float, 8, span);
+        let mantissa = self.append_binary(BinaryOperator::Add, high, low, span);
+
+        // Normal numbers have an implicit leading 1
+        let normal = self.append_binary(BinaryOperator::NotEqual, exponent, zero, span);
+        let implicit = self.get_double_constant(4503599627370496.0);
+        let none = self.get_double_constant(0.0);
+        let implicit = self.append_select(normal, implicit, none, span);
+        let mantissa = self.append_binary(BinaryOperator::Add, mantissa, implicit, span);
+
+        // Subnormal numbers share the exponent of the smallest normal ones,
+        // and the mantissa is an integer rather than a fraction
+        let one = self.get_scalar_constant(ScalarValue::Uint(1));
+        let exponent = self.append_math(MathFunction::Max, exponent, Some(one), span);
+        let exponent = self.append_convert(exponent, ScalarKind::Sint, 4, span);
+        let bias = self.get_scalar_constant(ScalarValue::Sint(1075));
+        let exponent = self.append_binary(BinaryOperator::Subtract, exponent, bias, span);
+        let magnitude = self.append_math(MathFunction::Ldexp, mantissa, Some(exponent), span);
+
+        let shift = self.get_scalar_constant(ScalarValue::Uint(31));
+        let sign = self.append_binary(BinaryOperator::ShiftRight, hi, shift, span);
+        let negative = self.append_binary(BinaryOperator::NotEqual, sign, zero, span);
+        let negated = Expression::Unary {
+            op: UnaryOperator::Negate,
+            expr: magnitude,
+        };
+        let negated = self.function.expressions.append(negated, span);
+        self.append_select(negative, negated, magnitude, span)
+    }
+
+    /// Get the low and high bits of an `f64` as `u32`s, the inverse of
+    /// [`get_double_from_bits`][Self::get_double_from_bits].
+    ///
+    /// The exponent is found with a binary search over every power of two,
+    /// since `naga` can't get it from the value directly. `-0.0` loses its
+    /// sign and NaNs come out as infinities.
+    fn get_double_bits(
+        &mut self,
+        value: Handle<Expression>,
+        span: Span,
+    ) -> (Handle<Expression>, Handle<Expression>) {
+        let zero = self.get_double_constant(0.0);
+        let negative = self.append_binary(BinaryOperator::Less, value, zero, span);
+        let sign = self.get_scalar_constant(ScalarValue::Uint(0x80000000));
+        let positive = self.get_scalar_constant(ScalarValue::Uint(0));
+        let sign = self.append_select(negative, sign, positive, span);
+        let value = self.append_math(MathFunction::Abs, value, None, span);
+
+        // Largest power of two that's at most the value, or 2^-1075 if the
+        // value is 0
+        let one = self.get_double_constant(1.0);
+        let mut exponent = self.get_scalar_constant(ScalarValue::Sint(-1075));
+        for bit in (0..12).rev() {
+            let step = self.get_scalar_constant(ScalarValue::Sint(1 << bit));
+            let candidate = self.append_binary(BinaryOperator::Add, exponent, step, span);
+            let power = self.append_math(MathFunction::Ldexp, one, Some(candidate), span);
+            let fits = self.append_binary(BinaryOperator::GreaterEqual, value, power, span);
+            exponent = self.append_select(fits, candidate, exponent, span);
+        }
+        let bias = self.get_scalar_constant(ScalarValue::Sint(1023));
+        let biased = self.append_binary(BinaryOperator::Add, exponent, bias, span);
+        let min = self.get_scalar_constant(ScalarValue::Sint(0));
+        let max = self.get_scalar_constant(ScalarValue::Sint(0x7FF));
+        let expr = Expression::Math {
+            fun: MathFunction::Clamp,
+            arg: biased,
+            arg1: Some(min),
+            arg2: Some(max),
+            arg3: None,
+        };
+        let biased = self.function.expressions.append(expr, span);
+
+        // Scale the mantissa to an integer, with subnormal numbers scaled
+        // like the smallest normal ones
+        let smallest = self.get_scalar_constant(ScalarValue::Sint(-1022));
+        let exponent = self.append_math(MathFunction::Max, exponent, Some(smallest), span);
+        let bits = self.get_scalar_constant(ScalarValue::Sint(52));
+        let scale = self.append_binary(BinaryOperator::Subtract, bits, exponent, span);
+        let mantissa = self.append_math(MathFunction::Ldexp, value, Some(scale), span);
+        let normal = self.append_binary(BinaryOperator::Greater, biased, min, span);
+        let implicit = self.get_double_constant(4503599627370496.0);
+        let implicit = self.append_select(normal, implicit, zero, span);
+        let mantissa = self.append_binary(BinaryOperator::Subtract, mantissa, implicit, span);
+        // Infinities don't have a mantissa
+        let infinite = self.append_binary(BinaryOperator::Equal, biased, max, span);
+        let mantissa = self.append_select(infinite, zero, mantissa, span);
+
+        let scale = self.get_double_constant(4294967296.0);
+        let high = self.append_binary(BinaryOperator::Divide, mantissa, scale, span);
+        let high = self.append_math(MathFunction::Floor, high, None, span);
+        let shifted = self.append_binary(BinaryOperator::Multiply, high, scale, span);
+        let low = self.append_binary(BinaryOperator::Subtract, mantissa, shifted, span);
+        let lo = self.append_convert(low, ScalarKind::Uint, 4, span);
+        let high = self.append_convert(high, ScalarKind::Uint, 4, span);
+
+        let biased = self.append_convert(biased, ScalarKind::Uint, 4, span);
+        let shift = self.get_scalar_constant(ScalarValue::Uint(20));
+        let biased = self.append_binary(BinaryOperator::ShiftLeft, biased, shift, span);
+        let hi = self.append_binary(BinaryOperator::InclusiveOr, high, biased, span);
+        let hi = self.append_binary(BinaryOperator::InclusiveOr, hi, sign, span);
+        (lo, hi)
+    }
+
+    /// Get the doubles a source operand selects as an `f64` scalar or vector.
+    ///
+    /// Registers hold the low and high bits of each double in a pair of
+    /// components, `.xy` or `.zw`.
+    pub(crate) fn get_src_double_expression(
+        &mut self,
+        op: &OperandToken0,
+        span: Span,
+    ) -> Handle<Expression> {
+        self.capabilities |= Capabilities::FLOAT64;
+        let pairs = get_selected_doubles(op);
+        let doubles: Vec<Handle<Expression>> = match op.get_operand_type() {
+            // Double literals are already one immediate per double
+            OperandType::Immediate64 => {
+                return self.get_immediate_expression(op, ScalarKind::Float, span);
+            }
+            // Doubles in constant buffers are members of their own
+            OperandType::ConstantBuffer => {
+                let register = self.get_register(op, span);
+                pairs
+                    .into_iter()
+                    .map(|pair| {
+                        // Panic safety: doubles are never padding
+                        let component = register[pair as usize * 2].unwrap();
+                        self.get_components_value(&[component], span)
+                    })
+                    .collect()
+            }
+            _ => {
+                // Swizzles move whole pairs, so the selected components are
+                // still low and high bits in turn
+                let bits = self.get_src_variable_expression(op, ScalarKind::Uint, span);
+                (0..pairs.len() as u32)
+                    .map(|i| {
+                        let lo = Expression::AccessIndex {
+                            base: bits,
+                            index: i * 2,
+                        };
+                        let lo = self.function.expressions.append(lo, span);
+                        let hi = Expression::AccessIndex {
+                            base: bits,
+                            index: i * 2 + 1,
+                        };
+                        let hi = self.function.expressions.append(hi, span);
+                        self.get_double_from_bits(lo, hi, span)
+                    })
+                    .collect()
+            }
+        };
+
+        if doubles.len() == 1 {
+            return doubles[0];
+        }
+        let ty = Type {
+            name: None,
+            inner: TypeInner::Vector {
+                size: get_vector_size(doubles.len()),
+                kind: ScalarKind::Float,
+                width: 8,
+            },
+        };
+        let ty = self.module.types.insert(ty, span);
+        let compose = Expression::Compose {
+            ty,
+            components: doubles,
+        };
+        self.function.expressions.append(compose, span)
+    }
+
+    /// Store a scalar or vector of `f64`s into the doubles a destination
+    /// operand selects, as the low and high bits in each pair of
+    /// components.
+    ///
+    /// Like every other write, vector components line up with the
+    /// destination's doubles.
+    pub(crate) fn get_dst_double_statement(
+        &mut self,
+        op: &OperandToken0,
+        span: Span,
+        value: Handle<Expression>,
+    ) -> Statement {
+        self.capabilities |= Capabilities::FLOAT64;
+        let is_vector = matches!(self.get_value_type(value), TypeInner::Vector { .. });
+
+        // Only the components the destination selects are stored
+        let unused = self.get_scalar_constant(ScalarValue::Uint(0));
+        let mut bits = vec![unused; 4];
+        for pair in get_selected_doubles(op) {
+            let value = if is_vector {
+                let expr = Expression::AccessIndex {
+                    base: value,
+                    index: pair,
+                };
+                self.function.expressions.append(expr, span)
+            } else {
+                value
+            };
+            let (lo, hi) = self.get_double_bits(value, span);
+            bits[pair as usize * 2] = lo;
+            bits[pair as usize * 2 + 1] = hi;
+        }
+
+        let ty = Type {
+            name: None,
+            inner: TypeInner::Vector {
+                size: VectorSize::Quad,
+                kind: ScalarKind::Uint,
+                width: 4,
+            },
+        };
+        let ty = self.module.types.insert(ty, span);
+        let compose = Expression::Compose {
+            ty,
+            components: bits,
+        };
+        let bits = self.function.expressions.append(compose, span);
+        self.get_dst_variable_statement(op, span, bits)
+    }
+
+    /// Get a boolean scalar or vector that's true where an integer scalar or
+    /// vector isn't zero.
+    pub(crate) fn get_nonzero(
+        &mut self,
+        value: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let zero = self.get_constant_like(ScalarValue::Uint(0), value, span);
+        let expr = Expression::Binary {
+            op: BinaryOperator::NotEqual,
+            left: value,
+            right: zero,
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Get DXBC's `0xFFFFFFFF` for true and `0` for false from a boolean
+    /// scalar or vector.
+    pub(crate) fn get_mask(
+        &mut self,
+        condition: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let accept = self.get_constant_like(ScalarValue::Uint(0xFFFFFFFF), condition, span);
+        let reject = self.get_constant_like(ScalarValue::Uint(0), condition, span);
+        let expr = Expression::Select {
+            condition,
+            accept,
+            reject,
+        };
+        self.function.expressions.append(expr, span)
+    }
+
+    /// Get the [`ScalarKind`] a destination operand's register holds.
+    pub(crate) fn get_dst_kind(&mut self, op: &OperandToken0, span: Span) -> ScalarKind {
+        let register = self.get_register(op, span);
+        match register.iter().flatten().next() {
+            Some(c) => get_scalar_kind_and_size(&self.get_value_type(c.base)).0,
+            // Writes to nothing are dead, so any kind will do
+            None => ScalarKind::Float,
         }
     }
 }
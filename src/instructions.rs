@@ -1,13 +1,51 @@
+use dxbc::dr::shex::OperandType;
 use dxbc::dr::*;
+use naga::valid::Capabilities;
 use naga::{
-    BinaryOperator, Expression, LocalVariable, ScalarKind, Span, Statement, Type, TypeInner,
-    VectorSize,
+    ArraySize, BinaryOperator, Block, Constant, ConstantInner, EarlyDepthTest, Expression,
+    GlobalVariable, Handle, LocalVariable, MathFunction, ScalarKind, ScalarValue, Span, Statement,
+    StorageClass, SwizzleComponent, Type, TypeInner, VectorSize,
 };
+use std::mem::{replace, take};
 
-use crate::NagaConsumer;
+use crate::utils::{
+    get_first_immediate, get_interpolation, get_scalar_kind_and_size, get_vector_size, is_null,
+};
+use crate::{Error, NagaConsumer};
+
+/// An `if` or `loop` whose body is being lowered. Its statements go into
+/// the function's body until it's closed, so the block around it is kept
+/// here.
+pub(crate) enum OpenBlock {
+    If {
+        condition: Handle<Expression>,
+        /// Statements before the `else`, if there's been one.
+        accept: Option<Block>,
+        outer: Block,
+    },
+    Loop {
+        outer: Block,
+    },
+}
 
 // TODO: use trait to implement these on instructions themselves
 impl NagaConsumer {
+    pub(crate) fn handle_decl_global_flags(&mut self, dcl: &DclGlobalFlags) -> Option<Statement> {
+        if dcl.flags.contains(GlobalFlags::FORCE_EARLY_DEPTH_STENCIL) {
+            self.early_depth_test = Some(EarlyDepthTest { conservative: None });
+        }
+        if dcl
+            .flags
+            .contains(GlobalFlags::ENABLE_DOUBLE_PRECISION_FLOAT_OPS)
+        {
+            self.capabilities |= Capabilities::FLOAT64;
+        }
+        // The rest are either optimization hints or about resources that
+        // need no capabilities in naga
+
+        None
+    }
+
     pub(crate) fn handle_decl_temps(&mut self, span: Span, dcl: &DclTemps) -> Option<Statement> {
         let four_floats = Type {
             name: None,
@@ -19,7 +57,6 @@ impl NagaConsumer {
             },
         };
         let four_floats = self.module.types.insert(four_floats, span);
-        let len = self.function.expressions.len();
         for i in 0..dcl.register_count {
             let var = LocalVariable {
                 name: Some(format!("temp_{}", i)),
@@ -28,16 +65,171 @@ impl NagaConsumer {
             };
             let var = self.function.local_variables.append(var, span);
             let var = Expression::LocalVariable(var);
-            let var = self.function.expressions.append(var, span);
+            let var = self.append_unemitted(var, span);
             self.temps.push(var);
         }
 
-        Some(Statement::Emit(self.function.expressions.range_from(len)))
+        None
+    }
+
+    pub(crate) fn handle_decl_indexable_temp(
+        &mut self,
+        span: Span,
+        dcl: &DclIndexableTemp,
+    ) -> Option<Statement> {
+        // TODO: determine specific type of temporary - analyze fields written to?
+        let kind = ScalarKind::Float;
+        let inner = if dcl.num_components == 1 {
+            TypeInner::Scalar { kind, width: 4 }
+        } else {
+            TypeInner::Vector {
+                size: get_vector_size(dcl.num_components as usize),
+                kind,
+                width: 4,
+            }
+        };
+        let base = self.module.types.insert(Type { name: None, inner }, span);
+
+        let c = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Scalar {
+                width: 4,
+                value: ScalarValue::Uint(dcl.register_count as u64),
+            },
+        };
+        let c = self.module.constants.fetch_or_append(c, span);
+        let array = Type {
+            name: None,
+            inner: TypeInner::Array {
+                base,
+                size: ArraySize::Constant(c),
+                // Three-component vectors are aligned like four-component ones
+                stride: dcl.num_components.next_power_of_two() * 4,
+            },
+        };
+        let array = self.module.types.insert(array, span);
+
+        let var = LocalVariable {
+            name: Some(format!("indexable_temp_{}", dcl.register_index)),
+            ty: array,
+            init: None,
+        };
+        let var = self.function.local_variables.append(var, span);
+        let var = Expression::LocalVariable(var);
+        let var = self.append_unemitted(var, span);
+        self.indexable_temps.insert(dcl.register_index, var);
+
+        None
+    }
+
+    pub(crate) fn handle_decl_immediate_constant_buffer(
+        &mut self,
+        span: Span,
+        dcl: &DclImmediateConstantBuffer,
+    ) -> Option<Statement> {
+        // Keep the raw bits, reads reinterpret them as whatever they need
+        let kind = ScalarKind::Uint;
+        let vector = Type {
+            name: None,
+            inner: TypeInner::Vector {
+                size: VectorSize::Quad,
+                kind,
+                width: 4,
+            },
+        };
+        let vector = self.module.types.insert(vector, span);
+        let elements: Vec<Handle<Constant>> = dcl
+            .data
+            .chunks(4)
+            .map(|element| {
+                let components = element
+                    .iter()
+                    .map(|bits| {
+                        let c = Constant {
+                            name: None,
+                            specialization: None,
+                            inner: ConstantInner::Scalar {
+                                width: 4,
+                                value: ScalarValue::Uint(*bits as u64),
+                            },
+                        };
+                        self.module.constants.fetch_or_append(c, span)
+                    })
+                    .collect();
+                let c = Constant {
+                    name: None,
+                    specialization: None,
+                    inner: ConstantInner::Composite {
+                        ty: vector,
+                        components,
+                    },
+                };
+                self.module.constants.fetch_or_append(c, span)
+            })
+            .collect();
+
+        let len = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Scalar {
+                width: 4,
+                value: ScalarValue::Uint(elements.len() as u64),
+            },
+        };
+        let len = self.module.constants.fetch_or_append(len, span);
+        let array = Type {
+            name: None,
+            inner: TypeInner::Array {
+                base: vector,
+                size: ArraySize::Constant(len),
+                stride: 16,
+            },
+        };
+        let array = self.module.types.insert(array, span);
+        let c = Constant {
+            name: Some("icb".to_string()),
+            specialization: None,
+            inner: ConstantInner::Composite {
+                ty: array,
+                components: elements,
+            },
+        };
+        let c = self.module.constants.append(c, span);
+
+        // Constants can't be indexed at runtime, so copy it into a global
+        let global = GlobalVariable {
+            name: Some("icb".to_string()),
+            class: StorageClass::Private,
+            binding: None,
+            ty: array,
+            init: Some(c),
+        };
+        let global = self.module.global_variables.append(global, span);
+        self.immediate_constant_buffer = Some(global);
+
+        None
+    }
+
+    /// Record the interpolation of a pixel shader input. System values are
+    /// typed and bound from the input signature, so `dcl_input_ps_sgv` and
+    /// `dcl_input_ps_siv` only declare the interpolation too.
+    pub(crate) fn handle_decl_input_ps(
+        &mut self,
+        register: &OperandToken0,
+        interpolation: InterpolationMode,
+    ) -> Option<Statement> {
+        if let Some(interpolation) = get_interpolation(interpolation) {
+            let register = get_first_immediate(*register);
+            self.input_interpolations.insert(register, interpolation);
+        }
+
+        None
     }
 
     pub(crate) fn handle_add(&mut self, span: Span, add: &Add) -> Option<Statement> {
-        let a = self.get_src_variable_expression(&add.a, span);
-        let b = self.get_src_variable_expression(&add.b, span);
+        let a = self.get_src_variable_expression(&add.a, ScalarKind::Float, span);
+        let b = self.get_src_variable_expression(&add.b, ScalarKind::Float, span);
 
         let expr = Expression::Binary {
             op: BinaryOperator::Add,
@@ -50,18 +242,617 @@ impl NagaConsumer {
     }
 
     pub(crate) fn handle_mov(&mut self, span: Span, mov: &Mov) -> Option<Statement> {
-        let src = self.get_src_variable_expression(&mov.src, span);
+        // Moves are untyped, so read registers as whatever they're moved
+        // into. Immediates keep their bits and the store reinterprets them
+        let kind = match mov.src.get_operand_type() {
+            OperandType::Immediate32 => ScalarKind::Uint,
+            _ => self.get_dst_kind(&mov.dst, span),
+        };
+        let src = self.get_src_variable_expression(&mov.src, kind, span);
         Some(self.get_dst_variable_statement(&mov.dst, span, src))
     }
 
+    pub(crate) fn handle_double_binary(
+        &mut self,
+        span: Span,
+        op: BinaryOperator,
+        dst: &OperandToken0,
+        a: &OperandToken0,
+        b: &OperandToken0,
+    ) -> Option<Statement> {
+        let a = self.get_src_double_expression(a, span);
+        let b = self.get_src_double_expression(b, span);
+
+        let expr = Expression::Binary {
+            op,
+            left: a,
+            right: b,
+        };
+        let expr = self.function.expressions.append(expr, span);
+
+        Some(self.get_dst_double_statement(dst, span, expr))
+    }
+
+    pub(crate) fn handle_double_math(
+        &mut self,
+        span: Span,
+        fun: MathFunction,
+        dst: &OperandToken0,
+        a: &OperandToken0,
+        b: &OperandToken0,
+    ) -> Option<Statement> {
+        let a = self.get_src_double_expression(a, span);
+        let b = self.get_src_double_expression(b, span);
+
+        let expr = Expression::Math {
+            fun,
+            arg: a,
+            arg1: Some(b),
+            arg2: None,
+            arg3: None,
+        };
+        let expr = self.function.expressions.append(expr, span);
+
+        Some(self.get_dst_double_statement(dst, span, expr))
+    }
+
+    /// Compare doubles, writing a 32-bit mask per double like other
+    /// comparisons do.
+    pub(crate) fn handle_double_comparison(
+        &mut self,
+        span: Span,
+        op: BinaryOperator,
+        dst: &OperandToken0,
+        a: &OperandToken0,
+        b: &OperandToken0,
+    ) -> Option<Statement> {
+        let a = self.get_src_double_expression(a, span);
+        let b = self.get_src_double_expression(b, span);
+
+        let condition = Expression::Binary {
+            op,
+            left: a,
+            right: b,
+        };
+        let condition = self.function.expressions.append(condition, span);
+        let expr = self.get_mask(condition, span);
+
+        Some(self.get_dst_variable_statement(dst, span, expr))
+    }
+
+    pub(crate) fn handle_dmov(&mut self, span: Span, mov: &DMov) -> Option<Statement> {
+        let src = self.get_src_double_expression(&mov.src, span);
+        Some(self.get_dst_double_statement(&mov.dst, span, src))
+    }
+
+    pub(crate) fn handle_dmovc(&mut self, span: Span, movc: &DMovC) -> Option<Statement> {
+        let a = self.get_src_double_expression(&movc.a, span);
+        let b = self.get_src_double_expression(&movc.b, span);
+
+        // Each double uses the condition component at its position
+        let (_, doubles) = get_scalar_kind_and_size(&self.get_value_type(a));
+        let cond = self.get_src_variable_expression(&movc.cond, ScalarKind::Uint, span);
+        let (_, size) = get_scalar_kind_and_size(&self.get_value_type(cond));
+        let cond = if size == doubles {
+            cond
+        } else if doubles == 1 {
+            let expr = Expression::AccessIndex {
+                base: cond,
+                index: 0,
+            };
+            self.function.expressions.append(expr, span)
+        } else {
+            let mut pattern = [SwizzleComponent::X; 4];
+            pattern[1] = SwizzleComponent::Y;
+            let swizzle = Expression::Swizzle {
+                size: get_vector_size(doubles),
+                vector: cond,
+                pattern,
+            };
+            self.function.expressions.append(swizzle, span)
+        };
+        let condition = self.get_nonzero(cond, span);
+
+        let expr = Expression::Select {
+            condition,
+            accept: a,
+            reject: b,
+        };
+        let expr = self.function.expressions.append(expr, span);
+
+        Some(self.get_dst_double_statement(&movc.dst, span, expr))
+    }
+
+    pub(crate) fn handle_dtof(&mut self, span: Span, dtof: &DtoF) -> Option<Statement> {
+        let src = self.get_src_double_expression(&dtof.src, span);
+        let expr = Expression::As {
+            expr: src,
+            kind: ScalarKind::Float,
+            convert: Some(4),
+        };
+        let expr = self.function.expressions.append(expr, span);
+
+        Some(self.get_dst_variable_statement(&dtof.dst, span, expr))
+    }
+
+    pub(crate) fn handle_ftod(&mut self, span: Span, ftod: &FtoD) -> Option<Statement> {
+        let src = self.get_src_variable_expression(&ftod.src, ScalarKind::Float, span);
+        let expr = Expression::As {
+            expr: src,
+            kind: ScalarKind::Float,
+            convert: Some(8),
+        };
+        let expr = self.function.expressions.append(expr, span);
+
+        Some(self.get_dst_double_statement(&ftod.dst, span, expr))
+    }
+
+    /// Apply a bit [`MathFunction`] that DXBC and `naga` agree on to a `u32`
+    /// source.
+    pub(crate) fn handle_bit_math(
+        &mut self,
+        span: Span,
+        fun: MathFunction,
+        dst: &OperandToken0,
+        src: &OperandToken0,
+    ) -> Option<Statement> {
+        let src = self.get_src_variable_expression(src, ScalarKind::Uint, span);
+        let expr = Expression::Math {
+            fun,
+            arg: src,
+            arg1: None,
+            arg2: None,
+            arg3: None,
+        };
+        let expr = self.function.expressions.append(expr, span);
+
+        Some(self.get_dst_variable_statement(dst, span, expr))
+    }
+
+    /// Handle `firstbit_hi` and `firstbit_shi`, which count from the most
+    /// significant bit instead of the least like [`MathFunction::FindMsb`].
+    pub(crate) fn handle_firstbit_hi(
+        &mut self,
+        span: Span,
+        kind: ScalarKind,
+        dst: &OperandToken0,
+        src: &OperandToken0,
+    ) -> Option<Statement> {
+        let src = self.get_src_variable_expression(src, kind, span);
+        let none = self.get_scalar_constant(ScalarValue::Uint(u32::MAX as u64));
+        let last = self.get_scalar_constant(ScalarValue::Uint(31));
+
+        let mut scalars = Vec::with_capacity(4);
+        for sources in self.get_component_sources(dst, &[src], span) {
+            let msb = Expression::Math {
+                fun: MathFunction::FindMsb,
+                arg: sources[0],
+                arg1: None,
+                arg2: None,
+                arg3: None,
+            };
+            let msb = self.function.expressions.append(msb, span);
+            let msb = self.bitcast(msb, ScalarKind::Uint, span);
+
+            // Both return -1 if there's no bit to find
+            let condition = Expression::Binary {
+                op: BinaryOperator::Equal,
+                left: msb,
+                right: none,
+            };
+            let condition = self.function.expressions.append(condition, span);
+            let flipped = Expression::Binary {
+                op: BinaryOperator::Subtract,
+                left: last,
+                right: msb,
+            };
+            let flipped = self.function.expressions.append(flipped, span);
+            let select = Expression::Select {
+                condition,
+                accept: msb,
+                reject: flipped,
+            };
+            scalars.push(self.function.expressions.append(select, span));
+        }
+        let expr = self.get_composed_value(scalars, span);
+
+        Some(self.get_dst_variable_statement(dst, span, expr))
+    }
+
+    /// Handle `ubfe` and `ibfe`.
+    pub(crate) fn handle_bfe(
+        &mut self,
+        span: Span,
+        kind: ScalarKind,
+        dst: &OperandToken0,
+        width: &OperandToken0,
+        offset: &OperandToken0,
+        src: &OperandToken0,
+    ) -> Option<Statement> {
+        let width = self.get_src_variable_expression(width, ScalarKind::Uint, span);
+        let offset = self.get_src_variable_expression(offset, ScalarKind::Uint, span);
+        let src = self.get_src_variable_expression(src, kind, span);
+
+        let mut scalars = Vec::with_capacity(4);
+        for sources in self.get_component_sources(dst, &[width, offset, src], span) {
+            let (offset, count) = self.get_bitfield_range(sources[0], sources[1], span);
+            let expr = Expression::Math {
+                fun: MathFunction::ExtractBits,
+                arg: sources[2],
+                arg1: Some(offset),
+                arg2: Some(count),
+                arg3: None,
+            };
+            scalars.push(self.function.expressions.append(expr, span));
+        }
+        let expr = self.get_composed_value(scalars, span);
+
+        Some(self.get_dst_variable_statement(dst, span, expr))
+    }
+
+    pub(crate) fn handle_bfi(&mut self, span: Span, bfi: &Bfi) -> Option<Statement> {
+        let width = self.get_src_variable_expression(&bfi.width, ScalarKind::Uint, span);
+        let offset = self.get_src_variable_expression(&bfi.offset, ScalarKind::Uint, span);
+        let insert = self.get_src_variable_expression(&bfi.insert, ScalarKind::Uint, span);
+        let base = self.get_src_variable_expression(&bfi.base, ScalarKind::Uint, span);
+
+        let mut scalars = Vec::with_capacity(4);
+        for sources in self.get_component_sources(&bfi.dst, &[width, offset, insert, base], span) {
+            let (offset, count) = self.get_bitfield_range(sources[0], sources[1], span);
+            let expr = Expression::Math {
+                fun: MathFunction::InsertBits,
+                arg: sources[3],
+                arg1: Some(sources[2]),
+                arg2: Some(offset),
+                arg3: Some(count),
+            };
+            scalars.push(self.function.expressions.append(expr, span));
+        }
+        let expr = self.get_composed_value(scalars, span);
+
+        Some(self.get_dst_variable_statement(&bfi.dst, span, expr))
+    }
+
+    /// Get the offset and bit count of a DXBC bitfield.
+    ///
+    /// DXBC only uses the low 5 bits of the width and offset, and cuts the
+    /// field off at the most significant bit rather than leaving it
+    /// undefined.
+    fn get_bitfield_range(
+        &mut self,
+        width: Handle<Expression>,
+        offset: Handle<Expression>,
+        span: Span,
+    ) -> (Handle<Expression>, Handle<Expression>) {
+        let mask = self.get_scalar_constant(ScalarValue::Uint(31));
+        let bits = self.get_scalar_constant(ScalarValue::Uint(32));
+
+        let offset = Expression::Binary {
+            op: BinaryOperator::And,
+            left: offset,
+            right: mask,
+        };
+        let offset = self.function.expressions.append(offset, span);
+        let width = Expression::Binary {
+            op: BinaryOperator::And,
+            left: width,
+            right: mask,
+        };
+        let width = self.function.expressions.append(width, span);
+        let rest = Expression::Binary {
+            op: BinaryOperator::Subtract,
+            left: bits,
+            right: offset,
+        };
+        let rest = self.function.expressions.append(rest, span);
+        let count = Expression::Math {
+            fun: MathFunction::Min,
+            arg: width,
+            arg1: Some(rest),
+            arg2: None,
+            arg3: None,
+        };
+        let count = self.function.expressions.append(count, span);
+
+        (offset, count)
+    }
+
+    /// Handle `imul` and `umul`, which write the high and low 32 bits of the
+    /// 64-bit product.
+    pub(crate) fn handle_mul_wide(
+        &mut self,
+        span: Span,
+        kind: ScalarKind,
+        dst_hi: &OperandToken0,
+        dst_lo: &OperandToken0,
+        a: &OperandToken0,
+        b: &OperandToken0,
+    ) -> Option<Statement> {
+        // The low bits are the same either way, so work on the raw bits
+        let a = self.get_src_variable_expression(a, ScalarKind::Uint, span);
+        let b = self.get_src_variable_expression(b, ScalarKind::Uint, span);
+
+        let hi = if is_null(dst_hi) {
+            None
+        } else {
+            let hi = self.get_mul_hi(a, b, span);
+            let hi = if let ScalarKind::Sint = kind {
+                // Treating negative factors as unsigned adds 2^32 times the
+                // other factor to the product
+                let shift = self.get_constant_like(ScalarValue::Uint(31), a, span);
+                let a_sign = self.append_binary(BinaryOperator::ShiftRight, a, shift, span);
+                let b_sign = self.append_binary(BinaryOperator::ShiftRight, b, shift, span);
+                let a_fix = self.append_binary(BinaryOperator::Multiply, a_sign, b, span);
+                let b_fix = self.append_binary(BinaryOperator::Multiply, b_sign, a, span);
+                let hi = self.append_binary(BinaryOperator::Subtract, hi, a_fix, span);
+                self.append_binary(BinaryOperator::Subtract, hi, b_fix, span)
+            } else {
+                hi
+            };
+            Some(hi)
+        };
+        let lo = if is_null(dst_lo) {
+            None
+        } else {
+            Some(self.append_binary(BinaryOperator::Multiply, a, b, span))
+        };
+
+        self.get_dst_pair_statement(span, &[(dst_hi, hi), (dst_lo, lo)])
+    }
+
+    /// Get the high 32 bits of the product of two `u32`s by multiplying their
+    /// 16-bit halves.
+    fn get_mul_hi(
+        &mut self,
+        a: Handle<Expression>,
+        b: Handle<Expression>,
+        span: Span,
+    ) -> Handle<Expression> {
+        let mask = self.get_constant_like(ScalarValue::Uint(0xFFFF), a, span);
+        let shift = self.get_constant_like(ScalarValue::Uint(16), a, span);
+
+        let a_lo = self.append_binary(BinaryOperator::And, a, mask, span);
+        let a_hi = self.append_binary(BinaryOperator::ShiftRight, a, shift, span);
+        let b_lo = self.append_binary(BinaryOperator::And, b, mask, span);
+        let b_hi = self.append_binary(BinaryOperator::ShiftRight, b, shift, span);
+
+        let ll = self.append_binary(BinaryOperator::Multiply, a_lo, b_lo, span);
+        let lh = self.append_binary(BinaryOperator::Multiply, a_lo, b_hi, span);
+        let hl = self.append_binary(BinaryOperator::Multiply, a_hi, b_lo, span);
+        let hh = self.append_binary(BinaryOperator::Multiply, a_hi, b_hi, span);
+
+        // Carry out of the middle 32 bits
+        let ll_hi = self.append_binary(BinaryOperator::ShiftRight, ll, shift, span);
+        let lh_lo = self.append_binary(BinaryOperator::And, lh, mask, span);
+        let hl_lo = self.append_binary(BinaryOperator::And, hl, mask, span);
+        let mid = self.append_binary(BinaryOperator::Add, ll_hi, lh_lo, span);
+        let mid = self.append_binary(BinaryOperator::Add, mid, hl_lo, span);
+        let carry = self.append_binary(BinaryOperator::ShiftRight, mid, shift, span);
+
+        let lh_hi = self.append_binary(BinaryOperator::ShiftRight, lh, shift, span);
+        let hl_hi = self.append_binary(BinaryOperator::ShiftRight, hl, shift, span);
+        let hi = self.append_binary(BinaryOperator::Add, hh, lh_hi, span);
+        let hi = self.append_binary(BinaryOperator::Add, hi, hl_hi, span);
+        self.append_binary(BinaryOperator::Add, hi, carry, span)
+    }
+
+    /// Handle `udiv`, which gives `0xFFFFFFFF` for both results when
+    /// dividing by zero.
+    pub(crate) fn handle_udiv(&mut self, span: Span, udiv: &UDiv) -> Option<Statement> {
+        let a = self.get_src_variable_expression(&udiv.a, ScalarKind::Uint, span);
+        let b = self.get_src_variable_expression(&udiv.b, ScalarKind::Uint, span);
+
+        let zero = self.get_constant_like(ScalarValue::Uint(0), b, span);
+        let one = self.get_constant_like(ScalarValue::Uint(1), b, span);
+        let max = self.get_constant_like(ScalarValue::Uint(0xFFFFFFFF), b, span);
+        let condition = self.append_binary(BinaryOperator::Equal, b, zero, span);
+        let divisor = Expression::Select {
+            condition,
+            accept: one,
+            reject: b,
+        };
+        let divisor = self.function.expressions.append(divisor, span);
+
+        let mut results = [(&udiv.dst_quot, None), (&udiv.dst_rem, None)];
+        for ((dst, result), op) in results
+            .iter_mut()
+            .zip([BinaryOperator::Divide, BinaryOperator::Modulo])
+        {
+            if is_null(*dst) {
+                continue;
+            }
+            let value = self.append_binary(op, a, divisor, span);
+            let select = Expression::Select {
+                condition,
+                accept: max,
+                reject: value,
+            };
+            *result = Some(self.function.expressions.append(select, span));
+        }
+
+        self.get_dst_pair_statement(span, &results)
+    }
+
+    /// Handle `uaddc` and `usubb`, which write the carry or borrow as `1` or
+    /// `0`.
+    pub(crate) fn handle_carry(
+        &mut self,
+        span: Span,
+        op: BinaryOperator,
+        dst: &OperandToken0,
+        dst_carry: &OperandToken0,
+        a: &OperandToken0,
+        b: &OperandToken0,
+    ) -> Option<Statement> {
+        let a = self.get_src_variable_expression(a, ScalarKind::Uint, span);
+        let b = self.get_src_variable_expression(b, ScalarKind::Uint, span);
+        let value = self.append_binary(op, a, b, span);
+
+        let carry = if is_null(dst_carry) {
+            None
+        } else {
+            // Adding wrapped around if the sum is smaller than either
+            // addend, subtracting did if the subtrahend is bigger
+            let condition = match op {
+                BinaryOperator::Add => self.append_binary(BinaryOperator::Less, value, a, span),
+                _ => self.append_binary(BinaryOperator::Less, a, b, span),
+            };
+            let accept = self.get_constant_like(ScalarValue::Uint(1), a, span);
+            let reject = self.get_constant_like(ScalarValue::Uint(0), a, span);
+            let select = Expression::Select {
+                condition,
+                accept,
+                reject,
+            };
+            Some(self.function.expressions.append(select, span))
+        };
+        let value = if is_null(dst) { None } else { Some(value) };
+
+        self.get_dst_pair_statement(span, &[(dst, value), (dst_carry, carry)])
+    }
+
+    /// Store results into the destinations of an instruction with two of
+    /// them, skipping the ones that are `null`.
+    fn get_dst_pair_statement(
+        &mut self,
+        span: Span,
+        results: &[(&OperandToken0, Option<Handle<Expression>>)],
+    ) -> Option<Statement> {
+        let mut statements = Vec::with_capacity(2);
+        for (dst, value) in results {
+            if let Some(value) = value {
+                statements.push(self.get_dst_variable_statement(dst, span, *value));
+            }
+        }
+
+        match statements.len() {
+            0 => None,
+            1 => statements.pop(),
+            _ => Some(Statement::Block(Block::from_vec(statements))),
+        }
+    }
+
+    pub(crate) fn handle_if(&mut self, span: Span, i: &If) -> Option<Statement> {
+        let condition = self.get_test_condition(&i.src, i.test, span);
+        // The condition is evaluated before the body
+        self.emit_expressions();
+        let outer = take(&mut self.function.body);
+        self.open_blocks.push(OpenBlock::If {
+            condition,
+            accept: None,
+            outer,
+        });
+        None
+    }
+
+    pub(crate) fn handle_else(&mut self) -> Option<Statement> {
+        self.emit_expressions();
+        match self.open_blocks.last_mut() {
+            Some(OpenBlock::If { accept: None, .. }) => {}
+            _ => {
+                self.fail(Error::UnbalancedControlFlow);
+                return None;
+            }
+        }
+        let body = take(&mut self.function.body);
+        if let Some(OpenBlock::If { accept, .. }) = self.open_blocks.last_mut() {
+            *accept = Some(body);
+        }
+        None
+    }
+
+    pub(crate) fn handle_end_if(&mut self) -> Option<Statement> {
+        self.emit_expressions();
+        let (condition, accept, outer) = match self.open_blocks.pop() {
+            Some(OpenBlock::If {
+                condition,
+                accept,
+                outer,
+            }) => (condition, accept, outer),
+            _ => {
+                self.fail(Error::UnbalancedControlFlow);
+                return None;
+            }
+        };
+        let body = replace(&mut self.function.body, outer);
+        let (accept, reject) = match accept {
+            Some(accept) => (accept, body),
+            None => (body, Block::new()),
+        };
+        Some(Statement::If {
+            condition,
+            accept,
+            reject,
+        })
+    }
+
+    pub(crate) fn handle_loop(&mut self) -> Option<Statement> {
+        self.emit_expressions();
+        let outer = take(&mut self.function.body);
+        self.open_blocks.push(OpenBlock::Loop { outer });
+        None
+    }
+
+    pub(crate) fn handle_end_loop(&mut self) -> Option<Statement> {
+        self.emit_expressions();
+        let outer = match self.open_blocks.pop() {
+            Some(OpenBlock::Loop { outer }) => outer,
+            _ => {
+                self.fail(Error::UnbalancedControlFlow);
+                return None;
+            }
+        };
+        let body = replace(&mut self.function.body, outer);
+        Some(Statement::Loop {
+            body,
+            continuing: Block::new(),
+        })
+    }
+
+    pub(crate) fn handle_breakc(&mut self, span: Span, breakc: &BreakC) -> Option<Statement> {
+        let condition = self.get_test_condition(&breakc.src, breakc.test, span);
+        let mut accept = Block::new();
+        accept.push(Statement::Break, span);
+
+        Some(Statement::If {
+            condition,
+            accept,
+            reject: Block::new(),
+        })
+    }
+
     pub(crate) fn handle_ret(&mut self, span: Span) -> Option<Statement> {
-        Some(match &self.function.result {
+        Some(self.get_return_statement(span))
+    }
+
+    pub(crate) fn handle_retc(&mut self, span: Span, retc: &RetC) -> Option<Statement> {
+        let condition = self.get_test_condition(&retc.src, retc.test, span);
+        let mut accept = Block::new();
+        accept.push(self.get_return_statement(span), span);
+
+        Some(Statement::If {
+            condition,
+            accept,
+            reject: Block::new(),
+        })
+    }
+
+    /// Get a [`Statement::Return`] of the outputs as they are at this point.
+    fn get_return_statement(&mut self, span: Span) -> Statement {
+        match &self.function.result {
             Some(r) => {
                 if let TypeInner::Struct { .. } = &self.module.types[r.ty].inner {
-                    let compose = Expression::Compose {
-                        ty: r.ty,
-                        components: self.outs.clone(),
-                    };
+                    let ty = r.ty;
+                    let components = self
+                        .outs
+                        .clone()
+                        .into_iter()
+                        .map(|pointer| {
+                            let load = Expression::Load { pointer };
+                            self.function.expressions.append(load, span)
+                        })
+                        .collect();
+                    let compose = Expression::Compose { ty, components };
                     let compose = self.function.expressions.append(compose, span);
                     Statement::Return {
                         value: Some(compose),
@@ -71,6 +862,6 @@ impl NagaConsumer {
                 }
             }
             None => Statement::Return { value: None },
-        })
+        }
     }
 }
@@ -1,14 +1,105 @@
 use dxbc::{
-    binary::Action,
-    dr::{IOsgnChunk, RdefChunk, RegisterComponentType, SemanticName, ShaderVariableClass},
+    binary::{Action, Consumer},
+    dr::{
+        IOsgnChunk, ProgramType, RdefChunk, RegisterComponentType, SemanticName, ShaderInputType,
+        ShaderType, ShaderVariableClass,
+    },
 };
+use naga::valid::Capabilities;
 use naga::{
-    Binding, BuiltIn, Expression, FunctionArgument, FunctionResult, GlobalVariable, Handle,
-    ScalarKind, Span, Statement, StorageClass, StructMember, Type, TypeInner,
+    ArraySize, Binding, BuiltIn, ConservativeDepth, Constant, ConstantInner, EarlyDepthTest,
+    Expression, FastHashMap, FunctionArgument, FunctionResult, GlobalVariable, Handle,
+    LocalVariable, ResourceBinding, ScalarKind, ScalarValue, Span, StorageClass, StructMember,
+    Type, TypeInner,
 };
+use std::fmt;
 
-use crate::utils::{get_scalar_kind, get_scalar_width, get_vector_size};
-use crate::NagaConsumer;
+use crate::expressions::Component;
+use crate::utils::{
+    get_builtin, get_scalar_kind, get_semantic_member_name, get_variable_width, get_vector_size,
+};
+use crate::{Error, NagaConsumer};
+
+/// HLSL semantic of a signature element, e.g. `TEXCOORD3`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Semantic {
+    /// Semantic name without the index, e.g. `TEXCOORD`.
+    pub name: String,
+    /// Semantic index, e.g. `3`.
+    pub index: u32,
+}
+
+impl fmt::Display for Semantic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.name, self.index)
+    }
+}
+
+/// HLSL semantics of an entry point's [`Binding::Location`]s.
+///
+/// `naga` only keeps locations, but pipelines link shader stages by semantic.
+#[derive(Clone, Debug, Default)]
+pub struct Semantics {
+    /// Semantics of inputs by location.
+    pub inputs: FastHashMap<u32, Semantic>,
+    /// Semantics of outputs by location.
+    pub outputs: FastHashMap<u32, Semantic>,
+}
+
+/// Order a matrix in a constant buffer is stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatrixMajority {
+    /// Each register holds a row. `naga` matrices are column-major, so
+    /// these are converted transposed, with HLSL's rows as columns.
+    Row,
+    /// Each register holds a column.
+    Column,
+}
+
+/// Majorities of the matrices in constant buffers by global variable and
+/// byte offset into it. Arrays of matrices have an entry per element.
+pub type MatrixMajorities = FastHashMap<(Handle<GlobalVariable>, u32), MatrixMajority>;
+
+/// Locations of varyings passed between shader stages by semantic.
+pub type VaryingLocations = FastHashMap<Semantic, u32>;
+
+/// How a resource's DXBC register space and register map to a `naga`
+/// [`ResourceBinding`].
+#[derive(Clone, Copy, Debug)]
+pub enum BindingMap {
+    /// Use the register space as the group and the register as the binding,
+    /// so `register(b3, space1)` is group 1, binding 3.
+    SpaceAsGroup,
+    /// Get the binding from the register space and register.
+    Custom(fn(space: u32, register: u32) -> ResourceBinding),
+}
+
+impl BindingMap {
+    /// Get the [`ResourceBinding`] of a resource.
+    pub fn get(&self, space: u32, register: u32) -> ResourceBinding {
+        match self {
+            BindingMap::SpaceAsGroup => ResourceBinding {
+                group: space,
+                binding: register,
+            },
+            BindingMap::Custom(f) => f(space, register),
+        }
+    }
+}
+
+impl Default for BindingMap {
+    fn default() -> Self {
+        BindingMap::SpaceAsGroup
+    }
+}
+
+/// Input struct members waiting for the declarations that complete them.
+pub(crate) struct PendingInputs {
+    /// Struct members along with the register they're read from.
+    members: Vec<(u32, StructMember)>,
+    /// Size of the struct.
+    span: u32,
+}
 
 /// Where [`NagaConsumer::get_io_elements`] is called from.
 enum IoCaller {
@@ -16,55 +107,114 @@ enum IoCaller {
     Output,
 }
 
+/// Whether user-defined elements of a signature are passed between shader
+/// stages, as opposed to being vertex attributes or render targets.
+fn is_varying(program_ty: ProgramType, caller: &IoCaller) -> bool {
+    matches!(
+        (program_ty, caller),
+        (ProgramType::Vertex, IoCaller::Output) | (ProgramType::Pixel, IoCaller::Input)
+    )
+}
+
+/// Round a constant buffer offset or size up to the next 16-byte register.
+fn round_to_register(size: u32) -> u32 {
+    (size + 15) / 16 * 16
+}
+
+/// [`dxbc`] [`Consumer`] to collect the semantics of varyings without
+/// converting the shader.
+#[derive(Default)]
+pub(crate) struct VaryingsConsumer {
+    /// Program type. Vertex, pixel, etc.
+    program_ty: Option<ProgramType>,
+    /// Semantics of varyings in signature order.
+    pub varyings: Vec<Semantic>,
+}
+
+impl VaryingsConsumer {
+    /// Collect varyings from a signature chunk if they're passed between stages.
+    fn collect(&mut self, chunk: &IOsgnChunk, caller: &IoCaller) {
+        let program_ty = match self.program_ty {
+            Some(ty) => ty,
+            None => return,
+        };
+        if !is_varying(program_ty, caller) {
+            return;
+        }
+
+        for elem in &chunk.elements {
+            if let SemanticName::Undefined = elem.semantic_type {
+                self.varyings.push(Semantic {
+                    name: elem.name.clone(),
+                    index: elem.semantic_index,
+                });
+            }
+        }
+    }
+}
+
+impl Consumer for VaryingsConsumer {
+    fn consume_rdef(&mut self, rdef: &RdefChunk) -> Action {
+        self.program_ty = Some(rdef.program_ty);
+        Action::Continue
+    }
+
+    fn consume_isgn(&mut self, isgn: &IOsgnChunk) -> Action {
+        self.collect(isgn, &IoCaller::Input);
+        Action::Continue
+    }
+
+    fn consume_osgn(&mut self, osgn: &IOsgnChunk) -> Action {
+        self.collect(osgn, &IoCaller::Output);
+        Action::Continue
+    }
+}
+
 impl NagaConsumer {
     /// Register all constant buffers found in an [RdefChunk].
     pub(crate) fn register_constant_buffers(&mut self, chunk: &RdefChunk) {
         for cb in &chunk.constant_buffers {
-            let mut inner = TypeInner::Struct {
-                members: Vec::new(),
-                span: 0,
+            // Operands refer to constant buffers by register, not by order
+            // in RDEF. Texture buffers share the chunk but don't have a
+            // CBuffer binding, and they're read like textures
+            let resource = match chunk
+                .resource_bindings
+                .iter()
+                .find(|b| matches!(b.input_type, ShaderInputType::CBuffer) && b.name == cb.name)
+            {
+                Some(resource) => resource,
+                None => continue,
             };
+            // Shader model 5.1 operands refer to buffers by a range ID,
+            // which RDEF sets to the bind point for earlier models
+            let id = resource.id;
 
-            // I Can't Believe It's Not Struct
-            if let TypeInner::Struct { members, span } = &mut inner {
-                for var in &cb.variables {
-                    let kind = match var.ty.class {
-                        ShaderVariableClass::Scalar
-                        | ShaderVariableClass::Vector
-                        | ShaderVariableClass::MatrixColumns => get_scalar_kind(var.ty.ty),
-                        _ => todo!(),
-                    };
-                    let width = get_scalar_width(kind);
-                    let inner = match var.ty.class {
-                        ShaderVariableClass::Scalar => TypeInner::Scalar { kind, width },
-                        ShaderVariableClass::Vector => TypeInner::Vector {
-                            size: get_vector_size(var.ty.columns.into()),
-                            kind,
-                            width,
-                        },
-                        ShaderVariableClass::MatrixColumns => TypeInner::Matrix {
-                            columns: get_vector_size(var.ty.columns.into()),
-                            rows: get_vector_size(var.ty.rows.into()),
-                            width,
-                        },
-                        _ => unreachable!(),
-                    };
-                    let ty = Type {
-                        name: Some(var.name.to_owned()),
-                        inner,
-                    };
-                    let ty = self.module.types.insert(ty, Span::UNDEFINED);
-
-                    let member = StructMember {
-                        name: Some(var.name.to_owned()),
-                        ty,
-                        binding: None,
-                        offset: var.offset,
-                    };
-                    members.push(member);
-                    *span = var.offset + var.size;
-                }
+            let mut members = Vec::with_capacity(cb.variables.len());
+            let mut majorities = Vec::new();
+            let mut span = 0;
+            for var in &cb.variables {
+                // Objects don't take up space in the buffer
+                let ty = match self.get_constant_buffer_type(
+                    &var.name,
+                    &var.ty,
+                    var.offset,
+                    &mut majorities,
+                ) {
+                    Some((ty, _)) => ty,
+                    None => continue,
+                };
+                members.push(StructMember {
+                    name: Some(var.name.to_owned()),
+                    ty,
+                    binding: None,
+                    offset: var.offset,
+                });
+                span = var.offset + var.size;
             }
+            let inner = TypeInner::Struct {
+                members,
+                span: round_to_register(span),
+            };
 
             let name = cb.name.to_owned();
             let ty = Type {
@@ -76,32 +226,225 @@ impl NagaConsumer {
             let global = GlobalVariable {
                 name: Some(name),
                 class: StorageClass::Uniform,
-                binding: None,
+                // Register spaces were added in SM 5.1, so they're 0 before
+                binding: Some(self.binding_map.get(resource.space, resource.bind_point)),
                 ty,
                 init: None,
             };
             let global = self.module.global_variables.append(global, Span::UNDEFINED);
-            let global = Expression::GlobalVariable(global);
-            let global = self.function.expressions.append(global, Span::UNDEFINED);
+            self.constant_buffers.insert(id, global);
+            for (offset, majority) in majorities {
+                self.matrix_majorities.insert((global, offset), majority);
+            }
+        }
+    }
 
-            let members = cb
-                .variables
-                .iter()
-                .enumerate()
-                .map(|(i, _)| {
-                    let expr = Expression::AccessIndex {
-                        base: global,
-                        index: i as u32,
+    /// Get the type of a constant buffer variable or struct member along with
+    /// its size in the buffer, or [`None`] if it's an object.
+    ///
+    /// The majorities of matrices in it are added to `majorities` by their
+    /// offset from `offset`, the variable's or member's own.
+    fn get_constant_buffer_type(
+        &mut self,
+        name: &str,
+        ty: &ShaderType,
+        offset: u32,
+        majorities: &mut Vec<(u32, MatrixMajority)>,
+    ) -> Option<(Handle<Type>, u32)> {
+        let first = majorities.len();
+        let (base, size) = self.get_constant_buffer_element_type(name, ty, offset, majorities)?;
+        if ty.elements == 0 {
+            return Some((base, size));
+        }
+
+        // Array elements always start in a new register
+        let elements = ty.elements as u32;
+        let stride = round_to_register(size);
+        let element_majorities = majorities[first..].to_vec();
+        for i in 1..elements {
+            let element_majorities = element_majorities.iter();
+            majorities.extend(element_majorities.map(|(o, m)| (o + i * stride, *m)));
+        }
+        let ty = Type {
+            name: None,
+            inner: self.get_constant_buffer_array(base, elements, stride),
+        };
+        let ty = self.module.types.insert(ty, Span::UNDEFINED);
+        Some((ty, stride * (elements - 1) + size))
+    }
+
+    /// Get an array of `elements` elements of type `base`, `stride` bytes
+    /// apart.
+    fn get_constant_buffer_array(
+        &mut self,
+        base: Handle<Type>,
+        elements: u32,
+        stride: u32,
+    ) -> TypeInner {
+        let c = Constant {
+            name: None,
+            specialization: None,
+            inner: ConstantInner::Scalar {
+                width: 4,
+                value: ScalarValue::Uint(elements as u64),
+            },
+        };
+        let c = self.module.constants.fetch_or_append(c, Span::UNDEFINED);
+        TypeInner::Array {
+            base,
+            size: ArraySize::Constant(c),
+            stride,
+        }
+    }
+
+    /// Get the type of a matrix whose `columns` columns each start in a new
+    /// register.
+    ///
+    /// `naga` only lays out matrices with 3 or 4 rows like HLSL does, so
+    /// columns of other matrices are put in an array instead. Columns of a
+    /// single row are scalars, and a single column is just a vector.
+    fn get_constant_buffer_matrix(&mut self, columns: u32, rows: u32) -> TypeInner {
+        let column = match rows {
+            1 => TypeInner::Scalar {
+                kind: ScalarKind::Float,
+                width: 4,
+            },
+            _ => TypeInner::Vector {
+                size: get_vector_size(rows as usize),
+                kind: ScalarKind::Float,
+                width: 4,
+            },
+        };
+        match (columns, rows) {
+            (1, _) => column,
+            (_, 3..=4) => TypeInner::Matrix {
+                columns: get_vector_size(columns as usize),
+                rows: get_vector_size(rows as usize),
+                width: 4,
+            },
+            _ => {
+                let column = Type {
+                    name: None,
+                    inner: column,
+                };
+                let column = self.module.types.insert(column, Span::UNDEFINED);
+                self.get_constant_buffer_array(column, columns, 16)
+            }
+        }
+    }
+
+    /// Get the type of a single element of a constant buffer variable or
+    /// struct member along with its size in the buffer, or [`None`] if it's
+    /// an object.
+    fn get_constant_buffer_element_type(
+        &mut self,
+        name: &str,
+        ty: &ShaderType,
+        offset: u32,
+        majorities: &mut Vec<(u32, MatrixMajority)>,
+    ) -> Option<(Handle<Type>, u32)> {
+        let columns = ty.columns as u32;
+        let rows = ty.rows as u32;
+        let (name, inner, size) = match ty.class {
+            // Components are at least 32 bits, even bools. Bools are kept as
+            // `u32`s, since `naga`'s can't be in a buffer
+            ShaderVariableClass::Scalar | ShaderVariableClass::Vector => {
+                let (kind, width) = match get_scalar_kind(ty.ty) {
+                    ScalarKind::Bool => (ScalarKind::Uint, 4),
+                    kind => (kind, get_variable_width(ty.ty)),
+                };
+                // `float1` is just a `float`
+                let inner = match columns {
+                    1 => TypeInner::Scalar { kind, width },
+                    _ => TypeInner::Vector {
+                        size: get_vector_size(columns as usize),
+                        kind,
+                        width,
+                    },
+                };
+                (None, inner, columns * width as u32)
+            }
+            // Each register holds a column
+            ShaderVariableClass::MatrixColumns => {
+                let inner = self.get_constant_buffer_matrix(columns, rows);
+                majorities.push((offset, MatrixMajority::Column));
+                (None, inner, (columns - 1) * 16 + rows * 4)
+            }
+            // Each register holds a row, so the matrix is stored transposed
+            // with HLSL's rows as columns
+            ShaderVariableClass::MatrixRows => {
+                let inner = self.get_constant_buffer_matrix(rows, columns);
+                majorities.push((offset, MatrixMajority::Row));
+                (None, inner, (rows - 1) * 16 + columns * 4)
+            }
+            ShaderVariableClass::Struct => {
+                let mut members = Vec::with_capacity(ty.members.len());
+                let mut size = 0;
+                for member in &ty.members {
+                    let (ty, member_size) = match self.get_constant_buffer_type(
+                        &member.name,
+                        &member.ty,
+                        offset + member.offset,
+                        majorities,
+                    ) {
+                        Some(ty) => ty,
+                        None => continue,
                     };
-                    self.function.expressions.append(expr, Span::UNDEFINED)
-                })
-                .collect();
-            self.constant_buffers.push(members);
+                    members.push(StructMember {
+                        name: Some(member.name.to_owned()),
+                        ty,
+                        binding: None,
+                        offset: member.offset,
+                    });
+                    size = member.offset + member_size;
+                }
+                let inner = TypeInner::Struct {
+                    members,
+                    span: round_to_register(size),
+                };
+                // Shader model 4 doesn't keep type names, so fall back to
+                // the variable's
+                let name = if ty.name.is_empty() {
+                    name
+                } else {
+                    ty.name.as_str()
+                };
+                (Some(name.to_owned()), inner, size)
+            }
+            _ => return None,
+        };
+        // Doubles validate only with the capability, even if never read
+        if let TypeInner::Scalar { width: 8, .. } | TypeInner::Vector { width: 8, .. } = inner {
+            self.capabilities |= Capabilities::FLOAT64;
         }
+
+        let ty = Type { name, inner };
+        let ty = self.module.types.insert(ty, Span::UNDEFINED);
+        Some((ty, size))
     }
 
-    /// Get a struct filled with inputs/outputs, if there are any.
-    fn get_io_elements(&mut self, chunk: &IOsgnChunk, caller: &IoCaller) -> Option<Handle<Type>> {
+    /// Get the location of a varying, assigning the next free location to
+    /// semantics that don't have one yet.
+    fn get_varying_location(&mut self, semantic: &Semantic) -> u32 {
+        if let Some(location) = self.varying_locations.get(semantic) {
+            return *location;
+        }
+
+        let location = self
+            .varying_locations
+            .values()
+            .max()
+            .map_or(0, |location| location + 1);
+        self.varying_locations.insert(semantic.clone(), location);
+        location
+    }
+
+    /// Get struct members for inputs/outputs and the size of their struct.
+    fn get_io_elements(
+        &mut self,
+        chunk: &IOsgnChunk,
+        caller: &IoCaller,
+    ) -> Result<(Vec<StructMember>, u32), Error> {
         let mut members = Vec::with_capacity(chunk.elements.len());
         let mut span = 0;
         let mut register = 0;
@@ -114,59 +457,98 @@ impl NagaConsumer {
                 RegisterComponentType::Unknown => todo!(),
             };
 
-            let zeros = 8 - elem.component_mask.leading_zeros();
-            let width = zeros * 4;
+            // Elements packed into the same register don't necessarily start
+            // at x, so count the components instead of the highest one
+            let components = elem.component_mask.count_ones();
+            let width = components * 4;
             // TODO: matrices? https://docs.rs/naga/latest/naga/enum.Binding.html#method.apply_default_interpolation
-            let inner = if zeros == 1 {
+            let inner = if components == 1 {
                 TypeInner::Scalar { kind, width: 4 }
             } else {
                 TypeInner::Vector {
-                    size: get_vector_size(zeros as usize),
+                    size: get_vector_size(components as usize),
                     kind,
                     width: 4,
                 }
             };
 
-            let binding = if let SemanticName::Undefined = elem.semantic_type {
-                // TODO: figure out what I should do with input names
-                let mut binding = Binding::Location {
-                    location: register,
+            let binding = match elem.semantic_type {
+                SemanticName::Undefined => {
+                    let semantic = Semantic {
+                        name: elem.name.clone(),
+                        index: elem.semantic_index,
+                    };
+                    let location = if is_varying(self.program_ty, caller) {
+                        self.get_varying_location(&semantic)
+                    } else {
+                        // We don't use the DXBC register because struct packing means several
+                        // elements fit into the same register and the Naga IR doesn't allow that:
+                        // https://docs.microsoft.com/en-us/windows/win32/direct3dhlsl/dx-graphics-hlsl-packing-rules
+                        let location = register;
+                        register += 1;
+                        location
+                    };
+                    let mut binding = Binding::Location {
+                        location,
+                        interpolation: None,
+                        sampling: None,
+                    };
+                    binding.apply_default_interpolation(&inner);
+                    binding
+                }
+                // Render targets can't be packed, so SV_TargetN always lives in
+                // oN and the semantic index is the location the pipeline
+                // expects. Fragment outputs also can't be interpolated.
+                SemanticName::Target => Binding::Location {
+                    location: elem.semantic_index,
                     interpolation: None,
                     sampling: None,
-                };
-                // TODO: figure out if autoincrementing registers screws things up anywhere
-                // We don't use the DXBC register because struct packing means several
-                // elements fit into the same register and the Naga IR doesn't allow that:
-                // https://docs.microsoft.com/en-us/windows/win32/direct3dhlsl/dx-graphics-hlsl-packing-rules
-                register += 1;
-                binding.apply_default_interpolation(&inner);
-                binding
-            } else {
-                let semantic = match elem.semantic_type {
-                    SemanticName::Undefined => unreachable!(),
-                    SemanticName::Position => BuiltIn::Position,
-                    SemanticName::ClipDistance => BuiltIn::ClipDistance,
-                    SemanticName::CullDistance => BuiltIn::CullDistance,
-                    SemanticName::RenderTargetArrayIndex => todo!(),
-                    SemanticName::ViewportArrayIndex => BuiltIn::ViewIndex,
-                    SemanticName::VertexId => BuiltIn::VertexIndex,
-                    SemanticName::PrimitiveId => BuiltIn::PrimitiveIndex,
-                    SemanticName::InstanceId => BuiltIn::InstanceIndex,
-                    SemanticName::IsFrontFace => BuiltIn::FrontFacing,
-                    SemanticName::SampleIndex => BuiltIn::SampleIndex,
-                    SemanticName::FinalQuadEdgeTessfactor => todo!(),
-                    SemanticName::FinalQuadInsideTessfactor => todo!(),
-                    SemanticName::FinalTriEdgeTessfactor => todo!(),
-                    SemanticName::FinalTriInsideTessfactor => todo!(),
-                    SemanticName::FinalLineDetailTessfactor => todo!(),
-                    SemanticName::FinalLineDensityTessfactor => todo!(),
-                    SemanticName::Target => todo!(),
-                    SemanticName::Depth => BuiltIn::FragDepth,
-                    SemanticName::Coverage => todo!(),
-                    SemanticName::DepthGreaterEqual => todo!(),
-                    SemanticName::DepthLessEqual => todo!(),
-                };
-                Binding::BuiltIn(semantic)
+                },
+                _ => {
+                    let builtin = get_builtin(elem.semantic_type)
+                        .ok_or(Error::UnsupportedSemantic(elem.semantic_type))?;
+
+                    // Conservative depth is written like any other depth, the
+                    // promise about its direction goes on the entry point
+                    let conservative = match elem.semantic_type {
+                        SemanticName::DepthGreaterEqual => Some(ConservativeDepth::GreaterEqual),
+                        SemanticName::DepthLessEqual => Some(ConservativeDepth::LessEqual),
+                        _ => None,
+                    };
+                    // Forcing early depth tests already promises more
+                    if conservative.is_some() && self.early_depth_test.is_none() {
+                        self.early_depth_test = Some(EarlyDepthTest { conservative });
+                    }
+
+                    self.capabilities |= match builtin {
+                        BuiltIn::PrimitiveIndex => Capabilities::PRIMITIVE_INDEX,
+                        BuiltIn::ClipDistance => Capabilities::CLIP_DISTANCE,
+                        BuiltIn::CullDistance => Capabilities::CULL_DISTANCE,
+                        _ => Capabilities::empty(),
+                    };
+
+                    Binding::BuiltIn(builtin)
+                }
+            };
+
+            // naga gives system values fixed types, whatever the signature
+            // declares them as
+            let inner = match binding {
+                Binding::BuiltIn(BuiltIn::FrontFacing) => TypeInner::Scalar {
+                    kind: ScalarKind::Bool,
+                    width: 1,
+                },
+                Binding::BuiltIn(
+                    BuiltIn::VertexIndex
+                    | BuiltIn::InstanceIndex
+                    | BuiltIn::PrimitiveIndex
+                    | BuiltIn::SampleIndex
+                    | BuiltIn::SampleMask,
+                ) => TypeInner::Scalar {
+                    kind: ScalarKind::Uint,
+                    width: 4,
+                },
+                _ => inner,
             };
 
             // Type construction is delayed because we need &inner for interpolation and sampling
@@ -177,9 +559,21 @@ impl NagaConsumer {
             };
             let ty = self.module.types.insert(ty, Span::UNDEFINED);
 
+            // Keep the original semantic of anything bound by location
+            if let Binding::Location { location, .. } = binding {
+                let semantics = match caller {
+                    IoCaller::Input => &mut self.semantics.inputs,
+                    IoCaller::Output => &mut self.semantics.outputs,
+                };
+                let semantic = Semantic {
+                    name: elem.name.clone(),
+                    index: elem.semantic_index,
+                };
+                semantics.insert(location, semantic);
+            }
+
             members.push(StructMember {
-                // TODO: create more sensible type name from fake semantic name
-                name: Some(elem.name.clone()),
+                name: Some(get_semantic_member_name(&elem.name, elem.semantic_index)),
                 // TODO: spans
                 ty,
                 binding: Some(binding),
@@ -188,45 +582,152 @@ impl NagaConsumer {
             span += width;
         }
 
-        let len = self.function.expressions.len();
-        for member in members.iter() {
-            let global = GlobalVariable {
-                name: member.name.clone(),
-                class: StorageClass::Private,
-                // TODO: find out if we need ResourceBindings on global variables
-                binding: None,
-                ty: member.ty,
-                init: None,
+        let argument = match caller {
+            IoCaller::Input if !self.flatten_inputs => {
+                let expr = Expression::FunctionArgument(0);
+                Some(self.append_unemitted(expr, Span::UNDEFINED))
+            }
+            _ => None,
+        };
+        for (i, (member, elem)) in members.iter().zip(&chunk.elements).enumerate() {
+            let (element, registers) = match caller {
+                IoCaller::Input => {
+                    let mut expr = match argument {
+                        Some(base) => {
+                            let expr = Expression::AccessIndex {
+                                base,
+                                index: i as u32,
+                            };
+                            self.function.expressions.append(expr, Span::UNDEFINED)
+                        }
+                        None => {
+                            let expr = Expression::FunctionArgument(i as u32);
+                            self.append_unemitted(expr, Span::UNDEFINED)
+                        }
+                    };
+                    // DXBC reads SV_IsFrontFace as a 0xFFFFFFFF/0 mask
+                    if let Some(Binding::BuiltIn(BuiltIn::FrontFacing)) = member.binding {
+                        let accept = self.get_scalar_constant(ScalarValue::Uint(u32::MAX as u64));
+                        let reject = self.get_scalar_constant(ScalarValue::Uint(0));
+                        let select = Expression::Select {
+                            condition: expr,
+                            accept,
+                            reject,
+                        };
+                        expr = self.function.expressions.append(select, Span::UNDEFINED);
+                    }
+                    (expr, &mut self.input_registers)
+                }
+                IoCaller::Output => {
+                    // Outputs that are never written still have to be returned
+                    let init = self.get_zero_constant(member.ty);
+                    let var = LocalVariable {
+                        name: member.name.clone(),
+                        ty: member.ty,
+                        init: Some(init),
+                    };
+                    let var = self.function.local_variables.append(var, Span::UNDEFINED);
+                    let expr = Expression::LocalVariable(var);
+                    let handle = self.append_unemitted(expr, Span::UNDEFINED);
+                    self.outs.push(handle);
+                    (handle, &mut self.output_registers)
+                }
             };
-            let global = self.module.global_variables.append(global, Span::UNDEFINED);
-            let expr = Expression::GlobalVariable(global);
-            let handle = self.function.expressions.append(expr, Span::UNDEFINED);
-            if let IoCaller::Output = caller {
-                self.outs.push(handle);
+
+            // Map each register component the element occupies to the
+            // matching component of the element
+            let first = elem.component_mask.trailing_zeros();
+            let scalar = elem.component_mask.count_ones() == 1;
+            let register = registers.entry(elem.register).or_default();
+            for (c, component) in register.iter_mut().enumerate() {
+                if elem.component_mask & (1 << c) != 0 {
+                    *component = Some(Component {
+                        base: element,
+                        index: if scalar { None } else { Some(c as u32 - first) },
+                    });
+                }
             }
         }
 
-        // Skip adding struct if it's empty
-        if self.function.expressions.len() - len > 0 {
-            let emit = Statement::Emit(self.function.expressions.range_from(len));
-            self.function.body.push(emit, Span::UNDEFINED);
+        Ok((members, span))
+    }
 
-            let ty = TypeInner::Struct { members, span };
-            let ty = Type {
-                name: None,
-                inner: ty,
-            };
-            let ty = self.module.types.insert(ty, Span::UNDEFINED);
-            Some(ty)
-        } else {
-            None
-        }
+    /// Insert a struct type for inputs/outputs.
+    fn get_io_struct(&mut self, members: Vec<StructMember>, span: u32) -> Handle<Type> {
+        let ty = TypeInner::Struct { members, span };
+        let ty = Type {
+            name: None,
+            inner: ty,
+        };
+        self.module.types.insert(ty, Span::UNDEFINED)
     }
 
-    /// Add function arguments from the [input chunk](IOsgnChunk).
+    /// Collect inputs from the [input chunk](IOsgnChunk).
+    ///
+    /// The function argument is added by
+    /// [`finish_inputs`][NagaConsumer::finish_inputs] because pixel shader
+    /// interpolation modes are only declared later in the shader.
     pub(crate) fn consume_isgn(&mut self, isgn: &IOsgnChunk) -> Action {
-        let s = self.get_io_elements(isgn, &IoCaller::Input);
-        if let Some(ty) = s {
+        let (members, span) = match self.get_io_elements(isgn, &IoCaller::Input) {
+            Ok(s) => s,
+            Err(e) => return self.fail(e),
+        };
+        // Skip adding struct if it's empty
+        if !members.is_empty() {
+            let members = isgn
+                .elements
+                .iter()
+                .map(|elem| elem.register)
+                .zip(members)
+                .collect();
+            self.pending_inputs = Some(PendingInputs { members, span });
+        }
+
+        Action::Continue
+    }
+
+    /// Add function arguments from the collected inputs, applying the
+    /// interpolation modes declared for their registers.
+    ///
+    /// Does nothing after the first call.
+    pub(crate) fn finish_inputs(&mut self) {
+        let pending = match self.pending_inputs.take() {
+            Some(p) => p,
+            None => return,
+        };
+
+        let members: Vec<StructMember> = pending
+            .members
+            .into_iter()
+            .map(|(register, mut member)| {
+                let declared = self.input_interpolations.get(&register);
+                if let (
+                    Some(Binding::Location {
+                        interpolation,
+                        sampling,
+                        ..
+                    }),
+                    Some(&(declared_interpolation, declared_sampling)),
+                ) = (&mut member.binding, declared)
+                {
+                    *interpolation = Some(declared_interpolation);
+                    *sampling = declared_sampling;
+                }
+                member
+            })
+            .collect();
+
+        if self.flatten_inputs {
+            for member in members {
+                let arg = FunctionArgument {
+                    name: member.name,
+                    ty: member.ty,
+                    binding: member.binding,
+                };
+                self.function.arguments.push(arg);
+            }
+        } else {
+            let ty = self.get_io_struct(members, pending.span);
             let arg = FunctionArgument {
                 name: self.module.types[ty].name.clone(),
                 ty,
@@ -234,14 +735,17 @@ impl NagaConsumer {
             };
             self.function.arguments.push(arg);
         }
-
-        Action::Continue
     }
 
     /// Add function result from the [output chunk](IOsgnChunk).
     pub(crate) fn consume_osgn(&mut self, osgn: &IOsgnChunk) -> Action {
-        let s = self.get_io_elements(osgn, &IoCaller::Output);
-        if let Some(ty) = s {
+        let (members, span) = match self.get_io_elements(osgn, &IoCaller::Output) {
+            Ok(s) => s,
+            Err(e) => return self.fail(e),
+        };
+        // Skip adding struct if it's empty
+        if !members.is_empty() {
+            let ty = self.get_io_struct(members, span);
             let result = FunctionResult { ty, binding: None };
             self.function.result = Some(result);
         }
@@ -1,16 +1,27 @@
+mod error;
 #[forbid(missing_docs)]
 mod expressions;
 mod instructions;
 mod io;
+mod lift;
 mod macros;
 mod utils;
 
-pub use macros::MatchMacrosConsumer;
+pub use error::Error;
+use expressions::Register;
+use instructions::OpenBlock;
+pub use io::{BindingMap, MatrixMajorities, MatrixMajority, Semantic, Semantics, VaryingLocations};
+use io::{PendingInputs, VaryingsConsumer};
+use lift::{LiftedMacro, LiftedSignature};
+pub use macros::{find_macros, MacroMatch, MatchMacrosConsumer};
+use macros::{get_macro_regions, MacroRegion};
 use naga::front::Typifier;
+use utils::is_declaration;
 
+use std::collections::VecDeque;
 use std::mem::take;
 
-use dxbc::binary::{Action, Consumer, Parser, State};
+use dxbc::binary::{Action, Consumer, Parser};
 use dxbc::dr::*;
 use naga::valid::{Capabilities, ModuleInfo, ValidationFlags, Validator};
 use naga::*;
@@ -24,17 +35,57 @@ pub(crate) struct NagaConsumer {
     typifier: Typifier,
     /// Program type. Vertex, pixel, etc.
     program_ty: ProgramType,
-    /// Pointers to constant buffers as [`Expression::AccessIndex`]es of
-    /// [`Expression::GlobalVariable`]s.
-    constant_buffers: Vec<Vec<Handle<Expression>>>,
+    /// Constant buffers by the ID operands refer to them by.
+    constant_buffers: FastHashMap<u32, Handle<GlobalVariable>>,
     /// Pointers to temporary registers as [`Expression::LocalVariable`]s.
     temps: Vec<Handle<Expression>>,
-    /// Pointers to output struct members as [`Expression::GlobalVariable`]s.
+    /// Pointers to indexable temporary arrays as
+    /// [`Expression::LocalVariable`]s by register.
+    indexable_temps: FastHashMap<u32, Handle<Expression>>,
+    /// Immediate constant buffer copied into a private global.
+    immediate_constant_buffer: Option<Handle<GlobalVariable>>,
+    /// Pointers to output struct members as [`Expression::LocalVariable`]s.
     outs: Vec<Handle<Expression>>,
+    /// Input registers mapped to the signature elements packed into them.
+    input_registers: FastHashMap<u32, Register>,
+    /// Output registers mapped to the signature elements packed into them.
+    output_registers: FastHashMap<u32, Register>,
+    /// Inputs collected from the signature until the declarations are done.
+    pending_inputs: Option<PendingInputs>,
+    /// Interpolation and sampling declared for pixel shader input registers.
+    input_interpolations: FastHashMap<u32, (Interpolation, Option<Sampling>)>,
+    /// Original semantics of location-bound inputs and outputs.
+    semantics: Semantics,
+    /// Majorities of matrices in constant buffers.
+    matrix_majorities: MatrixMajorities,
+    /// Locations of varyings, including ones assigned during conversion.
+    varying_locations: VaryingLocations,
+    /// Whether each input is its own function argument.
+    flatten_inputs: bool,
+    /// Mapping from resource registers to bindings.
+    binding_map: BindingMap,
+    /// Capabilities the module needs to pass validation.
+    capabilities: Capabilities,
+    /// Early depth test of the entry point, set by conservative depth outputs.
+    early_depth_test: Option<EarlyDepthTest>,
+    /// `if`s and `loop`s the current instruction is in, innermost last.
+    open_blocks: Vec<OpenBlock>,
+    /// Number of expressions that are emitted or don't need emitting.
+    emitted: usize,
+    /// Macros to lower into functions, ordered by their instruction ranges.
+    macro_regions: VecDeque<MacroRegion>,
+    /// Number of instructions so far that can be part of a macro chain.
+    chain_index: usize,
+    /// Entry point state while a macro is lowered into its own function.
+    lifted: Option<LiftedMacro>,
+    /// Functions of lifted macros by signature.
+    lifted_functions: FastHashMap<LiftedSignature, Handle<Function>>,
+    /// First error encountered. Once set, the rest of the shader is skipped.
+    error: Option<Error>,
 }
 
 impl NagaConsumer {
-    fn new() -> Self {
+    fn new(options: &Options) -> Self {
         let module = Module::default();
         let function = Function {
             name: Some("main".to_string()),
@@ -45,16 +96,45 @@ impl NagaConsumer {
             function,
             typifier: Typifier::new(),
             program_ty: ProgramType::Vertex,
-            constant_buffers: Vec::new(),
+            constant_buffers: FastHashMap::default(),
             temps: Vec::new(),
+            indexable_temps: FastHashMap::default(),
+            immediate_constant_buffer: None,
             outs: Vec::new(),
+            input_registers: FastHashMap::default(),
+            output_registers: FastHashMap::default(),
+            pending_inputs: None,
+            input_interpolations: FastHashMap::default(),
+            semantics: Semantics::default(),
+            matrix_majorities: MatrixMajorities::default(),
+            varying_locations: options.varying_locations.clone(),
+            flatten_inputs: options.flatten_inputs,
+            binding_map: options.binding_map,
+            capabilities: Capabilities::empty(),
+            early_depth_test: None,
+            open_blocks: Vec::new(),
+            emitted: 0,
+            macro_regions: VecDeque::new(),
+            chain_index: 0,
+            lifted: None,
+            lifted_functions: FastHashMap::default(),
+            error: None,
         }
     }
+
+    /// Record an error to return from [`parse`] and skip the rest of the
+    /// shader.
+    fn fail(&mut self, error: Error) -> Action {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        Action::Continue
+    }
 }
 
 impl Default for NagaConsumer {
     fn default() -> Self {
-        Self::new()
+        Self::new(&Options::default())
     }
 }
 
@@ -83,23 +163,44 @@ impl Consumer for NagaConsumer {
     }
 
     fn consume_instruction(&mut self, offset: u32, instruction: SparseInstruction) -> Action {
+        if self.error.is_some() {
+            return Action::Continue;
+        }
+
         let span = Span::new(offset, offset + instruction.opcode.get_instruction_length());
 
+        if !is_declaration(&instruction.operands) {
+            self.finish_inputs();
+        }
+        let chain_index = self.get_chain_index(&instruction);
+        if let Some(index) = chain_index {
+            self.begin_macro(index, span);
+        }
+
         let statement = match instruction.operands {
             // Declarations
-            Operands::DclGlobalFlags(_) => None,
+            Operands::DclGlobalFlags(dcl) => self.handle_decl_global_flags(&dcl),
             Operands::DclInput(_) => None,
-            Operands::DclInputPs(_) => None,
+            Operands::DclInputPs(dcl) => {
+                self.handle_decl_input_ps(&dcl.register, dcl.interpolation)
+            }
             Operands::DclOutput(_) => None,
             Operands::DclConstantBuffer(_) => None,
             Operands::DclResource(_) => None,
             Operands::DclSampler(_) => None,
             Operands::DclOutputSiv(_) => None,
             Operands::DclOutputSgv(_) => None,
-            Operands::DclInputPsSiv(_) => None,
-            Operands::DclInputPsSgv(_) => None,
+            Operands::DclInputPsSiv(dcl) => {
+                self.handle_decl_input_ps(&dcl.register, dcl.interpolation)
+            }
+            Operands::DclInputPsSgv(dcl) => {
+                self.handle_decl_input_ps(&dcl.register, dcl.interpolation)
+            }
             Operands::DclTemps(dcl) => self.handle_decl_temps(span, &dcl),
-            Operands::DclIndexableTemp(_) => None,
+            Operands::DclIndexableTemp(dcl) => self.handle_decl_indexable_temp(span, &dcl),
+            Operands::DclImmediateConstantBuffer(dcl) => {
+                self.handle_decl_immediate_constant_buffer(span, &dcl)
+            }
             // Boolean
             Operands::And(_) => None,
             Operands::Eq(_) => None,
@@ -129,6 +230,81 @@ impl Consumer for NagaConsumer {
             Operands::Rsq(_) => None,
             Operands::SinCos(_) => None,
             Operands::Sqrt(_) => None,
+            // Doubles
+            Operands::DAdd(d) => {
+                self.handle_double_binary(span, BinaryOperator::Add, &d.dst, &d.a, &d.b)
+            }
+            Operands::DMul(d) => {
+                self.handle_double_binary(span, BinaryOperator::Multiply, &d.dst, &d.a, &d.b)
+            }
+            Operands::DMax(d) => {
+                self.handle_double_math(span, MathFunction::Max, &d.dst, &d.a, &d.b)
+            }
+            Operands::DMin(d) => {
+                self.handle_double_math(span, MathFunction::Min, &d.dst, &d.a, &d.b)
+            }
+            Operands::DEq(d) => {
+                self.handle_double_comparison(span, BinaryOperator::Equal, &d.dst, &d.a, &d.b)
+            }
+            Operands::DLt(d) => {
+                self.handle_double_comparison(span, BinaryOperator::Less, &d.dst, &d.a, &d.b)
+            }
+            Operands::DGe(d) => self.handle_double_comparison(
+                span,
+                BinaryOperator::GreaterEqual,
+                &d.dst,
+                &d.a,
+                &d.b,
+            ),
+            Operands::DNe(d) => {
+                self.handle_double_comparison(span, BinaryOperator::NotEqual, &d.dst, &d.a, &d.b)
+            }
+            Operands::DMov(mov) => self.handle_dmov(span, &mov),
+            Operands::DMovC(movc) => self.handle_dmovc(span, &movc),
+            Operands::DtoF(dtof) => self.handle_dtof(span, &dtof),
+            Operands::FtoD(ftod) => self.handle_ftod(span, &ftod),
+            // Bits
+            Operands::CountBits(c) => {
+                self.handle_bit_math(span, MathFunction::CountOneBits, &c.dst, &c.src)
+            }
+            Operands::FirstBitHi(f) => {
+                self.handle_firstbit_hi(span, ScalarKind::Uint, &f.dst, &f.src)
+            }
+            Operands::FirstBitSHi(f) => {
+                self.handle_firstbit_hi(span, ScalarKind::Sint, &f.dst, &f.src)
+            }
+            Operands::FirstBitLo(f) => {
+                self.handle_bit_math(span, MathFunction::FindLsb, &f.dst, &f.src)
+            }
+            Operands::BfRev(b) => {
+                self.handle_bit_math(span, MathFunction::ReverseBits, &b.dst, &b.src)
+            }
+            Operands::UBfe(b) => {
+                self.handle_bfe(span, ScalarKind::Uint, &b.dst, &b.width, &b.offset, &b.src)
+            }
+            Operands::IBfe(b) => {
+                self.handle_bfe(span, ScalarKind::Sint, &b.dst, &b.width, &b.offset, &b.src)
+            }
+            Operands::Bfi(bfi) => self.handle_bfi(span, &bfi),
+            // Wide integer math
+            Operands::IMul(m) => {
+                self.handle_mul_wide(span, ScalarKind::Sint, &m.dst_hi, &m.dst_lo, &m.a, &m.b)
+            }
+            Operands::UMul(m) => {
+                self.handle_mul_wide(span, ScalarKind::Uint, &m.dst_hi, &m.dst_lo, &m.a, &m.b)
+            }
+            Operands::UDiv(udiv) => self.handle_udiv(span, &udiv),
+            Operands::UAddC(u) => {
+                self.handle_carry(span, BinaryOperator::Add, &u.dst, &u.dst_carry, &u.a, &u.b)
+            }
+            Operands::USubB(u) => self.handle_carry(
+                span,
+                BinaryOperator::Subtract,
+                &u.dst,
+                &u.dst_borrow,
+                &u.a,
+                &u.b,
+            ),
             // Memory
             Operands::Mov(mov) => self.handle_mov(span, &mov),
             Operands::MovC(_) => None,
@@ -137,14 +313,15 @@ impl Consumer for NagaConsumer {
             Operands::Utof(_) => None,
             Operands::Ftou(_) => None,
             // Control flow
-            Operands::If(_) => None,
-            Operands::Else => None,
-            Operands::EndIf => None,
-            Operands::Loop => None,
-            Operands::EndLoop => None,
-            Operands::Break => None,
-            Operands::BreakC(_) => None,
+            Operands::If(i) => self.handle_if(span, &i),
+            Operands::Else => self.handle_else(),
+            Operands::EndIf => self.handle_end_if(),
+            Operands::Loop => self.handle_loop(),
+            Operands::EndLoop => self.handle_end_loop(),
+            Operands::Break => Some(Statement::Break),
+            Operands::BreakC(b) => self.handle_breakc(span, &b),
             Operands::Ret => self.handle_ret(span),
+            Operands::RetC(retc) => self.handle_retc(span, &retc),
             // Textures
             Operands::Sample(_) => None,
             Operands::SampleL(_) => None,
@@ -155,14 +332,28 @@ impl Consumer for NagaConsumer {
             }
         };
 
+        self.emit_expressions();
         if let Some(s) = statement {
             self.function.body.push(s, span);
         }
+        if let Some(index) = chain_index {
+            self.end_macro(index, span);
+        }
 
         Action::Continue
     }
 
     fn finalize(&mut self) -> Action {
+        if self.error.is_some() {
+            return Action::Continue;
+        }
+        // The rest of the entry point is still put aside in the open blocks
+        if !self.open_blocks.is_empty() {
+            return self.fail(Error::UnbalancedControlFlow);
+        }
+
+        self.finish_inputs();
+        self.emit_expressions();
         let entry_point = EntryPoint {
             name: "main".to_owned(),
             stage: match self.program_ty {
@@ -171,7 +362,7 @@ impl Consumer for NagaConsumer {
                 ProgramType::Compute => ShaderStage::Compute,
                 _ => unreachable!(),
             },
-            early_depth_test: None,
+            early_depth_test: self.early_depth_test,
             workgroup_size: [0, 0, 0],
             function: take(&mut self.function),
         };
@@ -180,17 +371,102 @@ impl Consumer for NagaConsumer {
     }
 }
 
-pub fn parse<T: AsRef<[u8]>>(shader_bytes: T) -> Result<(Module, ModuleInfo), State> {
-    let mut consumer = NagaConsumer::new();
+/// Options for converting a shader.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Locations of varyings passed between shader stages by semantic.
+    /// Varyings that aren't in the map get the next free location.
+    pub varying_locations: VaryingLocations,
+    /// Pass each input as its own bound entry point argument, like HLSL and
+    /// WGSL entry points usually do, instead of one struct of all inputs.
+    pub flatten_inputs: bool,
+    /// How resource registers map to group and binding indices.
+    pub binding_map: BindingMap,
+    /// Capabilities to validate the module against, e.g. the ones the device
+    /// supports. [`None`] allows all of them.
+    pub capabilities: Option<Capabilities>,
+    /// Lower instructions that match Unity macros into functions named after
+    /// them, called from the entry point, instead of inlining them.
+    pub replace_macros: bool,
+}
+
+/// A converted shader along with what `naga` IR can't express.
+pub struct Shader {
+    /// Converted module.
+    pub module: Module,
+    /// Validation info of the module.
+    pub info: ModuleInfo,
+    /// HLSL semantics of the entry point's location-bound inputs and outputs.
+    pub semantics: Semantics,
+    /// Whether matrices in constant buffers were stored row-major or
+    /// column-major, which `naga`'s types don't keep.
+    pub matrix_majorities: MatrixMajorities,
+    /// Capabilities the module needs, e.g. [`Capabilities::FLOAT64`] for
+    /// doubles. Validating against these is enough for it to pass.
+    pub capabilities: Capabilities,
+}
+
+pub fn parse<T: AsRef<[u8]>>(shader_bytes: T) -> Result<(Module, ModuleInfo), Error> {
+    parse_shader(shader_bytes).map(|shader| (shader.module, shader.info))
+}
+
+pub fn parse_shader<T: AsRef<[u8]>>(shader_bytes: T) -> Result<Shader, Error> {
+    parse_with_options(shader_bytes, &Options::default())
+}
+
+pub fn parse_with_options<T: AsRef<[u8]>>(
+    shader_bytes: T,
+    options: &Options,
+) -> Result<Shader, Error> {
+    let mut consumer = NagaConsumer::new(options);
+    if options.replace_macros {
+        consumer.macro_regions = get_macro_regions(shader_bytes.as_ref())?.into();
+    }
     let mut parser = Parser::new(shader_bytes.as_ref(), &mut consumer);
-    if let Err(e) = parser.parse() {
+    parser.parse().map_err(Error::Parse)?;
+    if let Some(e) = consumer.error.take() {
         return Err(e);
     }
 
-    let mut validator = Validator::new(ValidationFlags::all(), Capabilities::all());
-    let info = validator.validate(&consumer.module);
-    // TODO: better error handling
-    let info = info.unwrap();
+    let capabilities = options.capabilities.unwrap_or_else(Capabilities::all);
+    let mut validator = Validator::new(ValidationFlags::all(), capabilities);
+    let info = validator
+        .validate(&consumer.module)
+        .map_err(Error::Validation)?;
+
+    Ok(Shader {
+        module: consumer.module,
+        info,
+        semantics: consumer.semantics,
+        matrix_majorities: consumer.matrix_majorities,
+        capabilities: consumer.capabilities,
+    })
+}
+
+/// Convert the shaders of one pipeline so that varyings with the same
+/// semantic get the same location in every stage.
+///
+/// Locations are assigned in order of first appearance, so pass shaders in
+/// pipeline order.
+pub fn parse_pipeline<T: AsRef<[u8]>>(shaders: &[T]) -> Result<Vec<Shader>, Error> {
+    let mut varying_locations = VaryingLocations::default();
+    for shader_bytes in shaders {
+        let mut consumer = VaryingsConsumer::default();
+        let mut parser = Parser::new(shader_bytes.as_ref(), &mut consumer);
+        parser.parse().map_err(Error::Parse)?;
+
+        for semantic in consumer.varyings {
+            let location = varying_locations.len() as u32;
+            varying_locations.entry(semantic).or_insert(location);
+        }
+    }
 
-    Ok((consumer.module, info))
+    let options = Options {
+        varying_locations,
+        ..Options::default()
+    };
+    shaders
+        .iter()
+        .map(|shader_bytes| parse_with_options(shader_bytes, &options))
+        .collect()
 }
@@ -0,0 +1,383 @@
+use dxbc::dr::SparseInstruction;
+use naga::front::Typifier;
+use naga::{
+    Expression, FastHashMap, Function, FunctionArgument, FunctionResult, Handle, LocalVariable,
+    ScalarKind, Span, Statement, StructMember, Type, TypeInner, VectorSize,
+};
+use std::mem::{replace, take};
+
+use crate::expressions::{Component, Register};
+use crate::macros::{Instruction, MacroRegion, RegisterFile};
+use crate::utils::get_scalar_kind_and_size;
+use crate::NagaConsumer;
+
+/// Entry point state put aside while a macro is lowered into its own
+/// function.
+pub(crate) struct LiftedMacro {
+    region: MacroRegion,
+    function: Function,
+    typifier: Typifier,
+    temps: Vec<Handle<Expression>>,
+    input_registers: FastHashMap<u32, Register>,
+    output_registers: FastHashMap<u32, Register>,
+    emitted: usize,
+    /// Values of the function's arguments in the entry point.
+    arguments: Vec<Handle<Expression>>,
+}
+
+/// Signature of a lifted macro's function. Occurrences of a macro with the
+/// same one call the same function.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct LiftedSignature {
+    name: String,
+    /// Types of the arguments.
+    arguments: Vec<Handle<Type>>,
+    /// Number of components of each returned register.
+    results: Vec<usize>,
+    /// Where arguments are read and results are written, so occurrences
+    /// that use their registers differently don't share a function.
+    in_reads: Vec<(usize, u32)>,
+    out_writers: Vec<usize>,
+}
+
+/// Get the name of a register as a function argument or struct member.
+fn get_register_name(file: RegisterFile, register: u32) -> String {
+    match file {
+        RegisterFile::Temp => format!("temp_{}", register),
+        RegisterFile::Input => format!("input_{}", register),
+        RegisterFile::Output => format!("output_{}", register),
+    }
+}
+
+impl NagaConsumer {
+    /// Count an instruction that can be part of a macro chain, returning its
+    /// index among them.
+    pub(crate) fn get_chain_index(&mut self, instruction: &SparseInstruction) -> Option<usize> {
+        Instruction::try_from(instruction)?;
+        self.chain_index += 1;
+        Some(self.chain_index - 1)
+    }
+
+    /// Get the [Register] of a register file the current function uses.
+    fn get_file_register(&self, file: RegisterFile, register: u32) -> Register {
+        match file {
+            RegisterFile::Temp => {
+                let base = self.temps[register as usize];
+                [0, 1, 2, 3].map(|index| {
+                    Some(Component {
+                        base,
+                        index: Some(index),
+                    })
+                })
+            }
+            RegisterFile::Input => self
+                .input_registers
+                .get(&register)
+                .copied()
+                .unwrap_or_default(),
+            RegisterFile::Output => self
+                .output_registers
+                .get(&register)
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Append a `vec4<f32>` local variable standing in for a register.
+    fn append_register_variable(&mut self, name: String, span: Span) -> Handle<Expression> {
+        let ty = Type {
+            name: None,
+            inner: TypeInner::Vector {
+                size: VectorSize::Quad,
+                kind: ScalarKind::Float,
+                width: 4,
+            },
+        };
+        let ty = self.module.types.insert(ty, span);
+        let var = LocalVariable {
+            name: Some(name),
+            ty,
+            init: None,
+        };
+        let var = self.function.local_variables.append(var, span);
+        self.append_unemitted(Expression::LocalVariable(var), span)
+    }
+
+    /// Get a statement storing a [Component] of a value to a [Component] of
+    /// a register, reinterpreted as the register's kind.
+    fn get_component_store(&mut self, dst: Component, src: Component, span: Span) -> Statement {
+        let (kind, _) = get_scalar_kind_and_size(&self.get_value_type(dst.base));
+        let pointer = match dst.index {
+            Some(index) => {
+                let expr = Expression::AccessIndex {
+                    base: dst.base,
+                    index,
+                };
+                self.function.expressions.append(expr, span)
+            }
+            None => dst.base,
+        };
+        let value = self.get_components_value(&[src], span);
+        let value = self.bitcast(value, kind, span);
+        Statement::Store { pointer, value }
+    }
+
+    /// Start lowering instructions into a function of their own if the
+    /// macro to lift next starts at chain instruction `index`.
+    ///
+    /// Live-in registers become arguments. Temporaries are local to the
+    /// function, so the live-in ones are stored from their arguments first.
+    pub(crate) fn begin_macro(&mut self, index: usize, span: Span) {
+        match self.macro_regions.front() {
+            Some(region) if region.instruction_range.start == index => {}
+            _ => return,
+        }
+        // Panic safety: there's a region at the front
+        let region = self.macro_regions.pop_front().unwrap();
+
+        let mut arguments = Vec::new();
+        let mut function_arguments = Vec::new();
+        let mut argument_registers = Vec::new();
+        for (file, register, components) in &region.liveness.live_in {
+            // Reads of components nothing is packed into don't matter
+            let caller_register = self.get_file_register(*file, *register);
+            let components: Vec<u32> = components
+                .iter()
+                .copied()
+                .filter(|c| caller_register[*c as usize].is_some())
+                .collect();
+            if components.is_empty() {
+                continue;
+            }
+
+            // Panic safety: empty components were filtered out
+            let sources: Vec<Component> = components
+                .iter()
+                .map(|c| caller_register[*c as usize].unwrap())
+                .collect();
+            let value = self.get_components_value(&sources, span);
+            let ty = Type {
+                name: None,
+                inner: self.get_value_type(value),
+            };
+            let ty = self.module.types.insert(ty, span);
+            function_arguments.push(FunctionArgument {
+                name: Some(get_register_name(*file, *register)),
+                ty,
+                binding: None,
+            });
+            arguments.push(value);
+            argument_registers.push((*file, *register, components));
+        }
+
+        let function = Function {
+            name: Some(region.name.clone()),
+            arguments: function_arguments,
+            ..Function::default()
+        };
+        let lifted = LiftedMacro {
+            function: replace(&mut self.function, function),
+            typifier: replace(&mut self.typifier, Typifier::new()),
+            temps: take(&mut self.temps),
+            input_registers: take(&mut self.input_registers),
+            output_registers: take(&mut self.output_registers),
+            emitted: replace(&mut self.emitted, 0),
+            arguments,
+            region,
+        };
+
+        for i in 0..lifted.region.liveness.temp_count {
+            let temp = self.append_register_variable(format!("temp_{}", i), span);
+            self.temps.push(temp);
+        }
+        for (file, register, _) in &lifted.region.liveness.live_out {
+            if let RegisterFile::Output = file {
+                let name = get_register_name(*file, *register);
+                let base = self.append_register_variable(name, span);
+                let output = [0, 1, 2, 3].map(|index| {
+                    Some(Component {
+                        base,
+                        index: Some(index),
+                    })
+                });
+                self.output_registers.insert(*register, output);
+            }
+        }
+
+        let mut statements = Vec::new();
+        for (i, (file, register, components)) in argument_registers.into_iter().enumerate() {
+            let argument = Expression::FunctionArgument(i as u32);
+            let base = self.append_unemitted(argument, span);
+            let argument_components = (0..components.len()).map(|position| Component {
+                base,
+                index: (components.len() > 1).then(|| position as u32),
+            });
+            match file {
+                RegisterFile::Temp => {
+                    let temp = self.get_file_register(file, register);
+                    for (c, src) in components.iter().zip(argument_components) {
+                        // Panic safety: every temporary component exists
+                        let dst = temp[*c as usize].unwrap();
+                        statements.push(self.get_component_store(dst, src, span));
+                    }
+                }
+                RegisterFile::Input => {
+                    let mut input = Register::default();
+                    for (c, src) in components.iter().zip(argument_components) {
+                        input[*c as usize] = Some(src);
+                    }
+                    self.input_registers.insert(register, input);
+                }
+                // Outputs are write-only
+                RegisterFile::Output => {}
+            }
+        }
+        self.emit_expressions();
+        for statement in statements {
+            self.function.body.push(statement, span);
+        }
+
+        self.lifted = Some(lifted);
+    }
+
+    /// Return the live-out registers from a lifted macro's function, as a
+    /// struct if there's more than one.
+    fn append_lifted_return(&mut self, region: &MacroRegion, span: Span) {
+        let live_out = &region.liveness.live_out;
+
+        let mut values = Vec::with_capacity(live_out.len());
+        let mut members = Vec::with_capacity(live_out.len());
+        for (i, (file, register, components)) in live_out.iter().enumerate() {
+            // Panic safety: registers in the function have every component
+            let function_register = self.get_file_register(*file, *register);
+            let sources: Vec<Component> = components
+                .iter()
+                .map(|c| function_register[*c as usize].unwrap())
+                .collect();
+            let value = self.get_components_value(&sources, span);
+            let ty = Type {
+                name: None,
+                inner: self.get_value_type(value),
+            };
+            let ty = self.module.types.insert(ty, span);
+            members.push(StructMember {
+                name: Some(get_register_name(*file, *register)),
+                ty,
+                binding: None,
+                // Every member fits in a register
+                offset: 16 * i as u32,
+            });
+            values.push(value);
+        }
+
+        let (value, ty) = if values.len() == 1 {
+            (values[0], members[0].ty)
+        } else {
+            let ty = Type {
+                name: Some(format!("{}Result", region.name)),
+                inner: TypeInner::Struct {
+                    span: 16 * members.len() as u32,
+                    members,
+                },
+            };
+            let ty = self.module.types.insert(ty, span);
+            let compose = Expression::Compose {
+                ty,
+                components: values,
+            };
+            (self.function.expressions.append(compose, span), ty)
+        };
+        self.emit_expressions();
+        let ret = Statement::Return { value: Some(value) };
+        self.function.body.push(ret, span);
+        self.function.result = Some(FunctionResult { ty, binding: None });
+    }
+
+    /// Finish lowering a lifted macro if it ends at chain instruction
+    /// `index`, then call it from the entry point.
+    ///
+    /// Live-out registers are stored to the entry point's registers after
+    /// the call.
+    pub(crate) fn end_macro(&mut self, index: usize, span: Span) {
+        match &self.lifted {
+            Some(lifted) if lifted.region.instruction_range.end == index + 1 => {}
+            _ => return,
+        }
+        // Panic safety: there's a lifted macro
+        let lifted = self.lifted.take().unwrap();
+
+        // Occurrences of a macro with the same signature share a function
+        let signature = LiftedSignature {
+            name: lifted.region.name.clone(),
+            arguments: self.function.arguments.iter().map(|a| a.ty).collect(),
+            results: lifted
+                .region
+                .liveness
+                .live_out
+                .iter()
+                .map(|(_, _, components)| components.len())
+                .collect(),
+            in_reads: lifted.region.liveness.in_reads.clone(),
+            out_writers: lifted.region.liveness.out_writers.clone(),
+        };
+        let cached = self.lifted_functions.get(&signature).copied();
+        if cached.is_none() {
+            self.append_lifted_return(&lifted.region, span);
+        }
+
+        // Back to the entry point
+        let function = replace(&mut self.function, lifted.function);
+        self.typifier = lifted.typifier;
+        self.temps = lifted.temps;
+        self.input_registers = lifted.input_registers;
+        self.output_registers = lifted.output_registers;
+        self.emitted = lifted.emitted;
+        let function = match cached {
+            Some(function) => function,
+            None => {
+                let function = self.module.functions.append(function, span);
+                self.lifted_functions.insert(signature, function);
+                function
+            }
+        };
+
+        let result = self.append_unemitted(Expression::CallResult(function), span);
+        let call = Statement::Call {
+            function,
+            arguments: lifted.arguments,
+            result: Some(result),
+        };
+        self.function.body.push(call, span);
+
+        let live_out = &lifted.region.liveness.live_out;
+        let mut statements = Vec::new();
+        for (i, (file, register, components)) in live_out.iter().enumerate() {
+            let value = if live_out.len() == 1 {
+                result
+            } else {
+                let expr = Expression::AccessIndex {
+                    base: result,
+                    index: i as u32,
+                };
+                self.function.expressions.append(expr, span)
+            };
+            let caller_register = self.get_file_register(*file, *register);
+            for (position, c) in components.iter().enumerate() {
+                // Writes to components nothing is packed into are dead
+                let dst = match caller_register[*c as usize] {
+                    Some(dst) => dst,
+                    None => continue,
+                };
+                let src = Component {
+                    base: value,
+                    index: (components.len() > 1).then(|| position as u32),
+                };
+                statements.push(self.get_component_store(dst, src, span));
+            }
+        }
+        self.emit_expressions();
+        for statement in statements {
+            self.function.body.push(statement, span);
+        }
+    }
+}
@@ -1,8 +1,17 @@
 use dxbc::binary::{Action, Consumer, Parser};
-use dxbc::dr::{Operands, SparseInstruction};
+use dxbc::dr::shex::{Immediate, OperandType};
+use dxbc::dr::{OperandToken0, Operands, RdefChunk, ShaderInputType, SparseInstruction};
 use include_dir::{include_dir, Dir, DirEntry, File};
 use naga::FastHashMap;
 use once_cell::sync::Lazy;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::Range;
+
+use crate::utils::{
+    get_constant_buffer_slot, get_first_immediate, get_selected_components, is_declaration,
+};
+use crate::Error;
 
 static SHADER_DIR: Dir = include_dir!("shaders/compiled");
 
@@ -38,6 +47,34 @@ pub enum Instruction {
     Rsq,
     SinCos,
     Sqrt,
+    // Doubles
+    DAdd,
+    DMul,
+    DMax,
+    DMin,
+    DEq,
+    DLt,
+    DGe,
+    DNe,
+    DMov,
+    DMovC,
+    DtoF,
+    FtoD,
+    // Bits
+    CountBits,
+    FirstBitHi,
+    FirstBitSHi,
+    FirstBitLo,
+    BfRev,
+    UBfe,
+    IBfe,
+    Bfi,
+    // Wide integer math
+    IMul,
+    UMul,
+    UDiv,
+    UAddC,
+    USubB,
     // Memory
     Mov,
     MovC,
@@ -63,8 +100,8 @@ pub enum Instruction {
 impl Instruction {
     /// Get an [`Instruction`] from [`dxbc`]'s [`SparseInstruction`] if it
     /// should be included in an instruction chain.
-    fn try_from(instruction: SparseInstruction) -> Option<Self> {
-        match instruction.operands {
+    pub(crate) fn try_from(instruction: &SparseInstruction) -> Option<Self> {
+        match &instruction.operands {
             // Declarations
             Operands::DclGlobalFlags(_) => None,
             Operands::DclInput(_) => None,
@@ -79,6 +116,7 @@ impl Instruction {
             Operands::DclInputPsSgv(_) => None,
             Operands::DclTemps(_) => None,
             Operands::DclIndexableTemp(_) => None,
+            Operands::DclImmediateConstantBuffer(_) => None,
             // Boolean
             Operands::And(_) => Some(Instruction::And),
             Operands::Eq(_) => Some(Instruction::Eq),
@@ -109,6 +147,34 @@ impl Instruction {
             Operands::Rsq(_) => Some(Instruction::Rsq),
             Operands::SinCos(_) => Some(Instruction::SinCos),
             Operands::Sqrt(_) => Some(Instruction::Sqrt),
+            // Doubles
+            Operands::DAdd(_) => Some(Instruction::DAdd),
+            Operands::DMul(_) => Some(Instruction::DMul),
+            Operands::DMax(_) => Some(Instruction::DMax),
+            Operands::DMin(_) => Some(Instruction::DMin),
+            Operands::DEq(_) => Some(Instruction::DEq),
+            Operands::DLt(_) => Some(Instruction::DLt),
+            Operands::DGe(_) => Some(Instruction::DGe),
+            Operands::DNe(_) => Some(Instruction::DNe),
+            Operands::DMov(_) => Some(Instruction::DMov),
+            Operands::DMovC(_) => Some(Instruction::DMovC),
+            Operands::DtoF(_) => Some(Instruction::DtoF),
+            Operands::FtoD(_) => Some(Instruction::FtoD),
+            // Bits
+            Operands::CountBits(_) => Some(Instruction::CountBits),
+            Operands::FirstBitHi(_) => Some(Instruction::FirstBitHi),
+            Operands::FirstBitSHi(_) => Some(Instruction::FirstBitSHi),
+            Operands::FirstBitLo(_) => Some(Instruction::FirstBitLo),
+            Operands::BfRev(_) => Some(Instruction::BfRev),
+            Operands::UBfe(_) => Some(Instruction::UBfe),
+            Operands::IBfe(_) => Some(Instruction::IBfe),
+            Operands::Bfi(_) => Some(Instruction::Bfi),
+            // Wide integer math
+            Operands::IMul(_) => Some(Instruction::IMul),
+            Operands::UMul(_) => Some(Instruction::UMul),
+            Operands::UDiv(_) => Some(Instruction::UDiv),
+            Operands::UAddC(_) => Some(Instruction::UAddC),
+            Operands::USubB(_) => Some(Instruction::USubB),
             // Memory
             Operands::Mov(_) => Some(Instruction::Mov),
             // Conversions
@@ -124,6 +190,7 @@ impl Instruction {
             Operands::Break => Some(Instruction::Break),
             Operands::BreakC(_) => Some(Instruction::BreakC),
             Operands::Ret => None,
+            Operands::RetC(_) => None,
             // Textures
             Operands::Sample(_) => Some(Instruction::Sample),
             Operands::SampleL(_) => Some(Instruction::SampleL),
@@ -133,14 +200,573 @@ impl Instruction {
     }
 }
 
-pub type MacroMap = FastHashMap<Vec<Instruction>, Vec<String>>;
+/// Get the destination and source operands of an instruction that can be part
+/// of a chain.
+fn get_operands(operands: &Operands) -> (Vec<&OperandToken0>, Vec<&OperandToken0>) {
+    match operands {
+        // Boolean
+        Operands::And(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Eq(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Ge(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Ige(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Lt(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Ne(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Or(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        // Math
+        Operands::Add(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Div(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Dp2(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Dp3(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Dp4(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Exp(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::Frc(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::IAdd(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Log(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::Mad(i) => (vec![&i.dst], vec![&i.a, &i.b, &i.c]),
+        Operands::Max(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Min(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::Mul(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::RoundNe(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::RoundNi(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::RoundPi(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::RoundZ(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::Rsq(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::SinCos(i) => (vec![&i.dst_sin, &i.dst_cos], vec![&i.src]),
+        Operands::Sqrt(i) => (vec![&i.dst], vec![&i.src]),
+        // Doubles
+        Operands::DAdd(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DMul(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DMax(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DMin(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DEq(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DLt(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DGe(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DNe(i) => (vec![&i.dst], vec![&i.a, &i.b]),
+        Operands::DMov(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::DMovC(i) => (vec![&i.dst], vec![&i.cond, &i.a, &i.b]),
+        Operands::DtoF(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::FtoD(i) => (vec![&i.dst], vec![&i.src]),
+        // Bits
+        Operands::CountBits(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::FirstBitHi(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::FirstBitSHi(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::FirstBitLo(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::BfRev(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::UBfe(i) => (vec![&i.dst], vec![&i.width, &i.offset, &i.src]),
+        Operands::IBfe(i) => (vec![&i.dst], vec![&i.width, &i.offset, &i.src]),
+        Operands::Bfi(i) => (vec![&i.dst], vec![&i.width, &i.offset, &i.insert, &i.base]),
+        // Wide integer math
+        Operands::IMul(i) => (vec![&i.dst_hi, &i.dst_lo], vec![&i.a, &i.b]),
+        Operands::UMul(i) => (vec![&i.dst_hi, &i.dst_lo], vec![&i.a, &i.b]),
+        Operands::UDiv(i) => (vec![&i.dst_quot, &i.dst_rem], vec![&i.a, &i.b]),
+        Operands::UAddC(i) => (vec![&i.dst, &i.dst_carry], vec![&i.a, &i.b]),
+        Operands::USubB(i) => (vec![&i.dst, &i.dst_borrow], vec![&i.a, &i.b]),
+        // Memory
+        Operands::Mov(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::MovC(i) => (vec![&i.dst], vec![&i.cond, &i.a, &i.b]),
+        // Conversions
+        Operands::Itof(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::Utof(i) => (vec![&i.dst], vec![&i.src]),
+        Operands::Ftou(i) => (vec![&i.dst], vec![&i.src]),
+        // Control flow
+        Operands::If(i) => (vec![], vec![&i.src]),
+        Operands::BreakC(i) => (vec![], vec![&i.src]),
+        Operands::RetC(i) => (vec![], vec![&i.src]),
+        // Textures
+        Operands::Sample(i) => (
+            vec![&i.dst],
+            vec![&i.src_address, &i.src_resource, &i.src_sampler],
+        ),
+        Operands::SampleL(i) => (
+            vec![&i.dst],
+            vec![&i.src_address, &i.src_resource, &i.src_sampler, &i.src_lod],
+        ),
+        // Declarations, `ret` and instructions without operands
+        _ => (vec![], vec![]),
+    }
+}
+
+/// Unity globals that macros read, whose reads are part of a chain's
+/// fingerprint. Other constant buffer reads are arguments of the macro.
+const UNITY_GLOBALS: &[&str] = &[
+    "_Time",
+    "_WorldSpaceCameraPos",
+    "_ProjectionParams",
+    "_ScreenParams",
+    "_ZBufferParams",
+    "unity_OrthoParams",
+    "unity_CameraProjection",
+    "unity_CameraInvProjection",
+    "unity_WorldToCamera",
+    "unity_CameraToWorld",
+    "_WorldSpaceLightPos0",
+    "unity_SHAr",
+    "unity_SHAg",
+    "unity_SHAb",
+    "unity_SHBr",
+    "unity_SHBg",
+    "unity_SHBb",
+    "unity_SHC",
+    "unity_LightShadowBias",
+    "_LightShadowData",
+    "unity_ObjectToWorld",
+    "unity_WorldToObject",
+    "unity_WorldTransformParams",
+    "glstate_matrix_projection",
+    "unity_MatrixV",
+    "unity_MatrixInvV",
+    "unity_MatrixVP",
+    "unity_StereoEyeIndex",
+];
+
+/// Where a source operand of an instruction gets its value from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+    /// Components of a temporary register, by the index of the instruction
+    /// that last wrote each one, or [`None`] if none did.
+    Temp(Vec<Option<usize>>),
+    /// Register of a well-known Unity global, relative to its first register.
+    Global(&'static str, u32),
+    /// Anything else, e.g. an input, an immediate or a constant buffer
+    /// variable that's an argument of the macro.
+    External,
+}
+
+/// Sources of every source operand of every instruction in a chain, with
+/// instruction indices relative to the start of the chain.
+///
+/// Macros that compile to the same instructions differ in what those
+/// instructions read, so this tells them apart where opcodes can't.
+pub type Fingerprint = Vec<Vec<Source>>;
+
+/// Register file that values flow through between instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum RegisterFile {
+    Temp,
+    Input,
+    Output,
+}
+
+impl RegisterFile {
+    /// Get the register file of an operand, if values flow through it.
+    fn from_operand(op: &OperandToken0) -> Option<Self> {
+        match op.get_operand_type() {
+            OperandType::Temp => Some(Self::Temp),
+            OperandType::Input => Some(Self::Input),
+            OperandType::Output => Some(Self::Output),
+            _ => None,
+        }
+    }
+}
+
+/// Read or write of a register component.
+struct Access {
+    /// Number of chain instructions before the access. Instructions outside
+    /// chains share the position of the next chain instruction.
+    position: usize,
+    /// Whether the access is by a chain instruction.
+    in_chain: bool,
+    file: RegisterFile,
+    register: u32,
+    component: u32,
+    /// Index of the instruction that last wrote a read temporary register
+    /// component.
+    writer: Option<usize>,
+}
+
+/// Components of registers by register file and register, in order.
+pub(crate) type RegisterComponents = Vec<(RegisterFile, u32, Vec<u32>)>;
+
+/// Registers a chain reads from before it and writes for after it.
+///
+/// Registers are in the order the chain first accesses them, so chains that
+/// do the same thing with different registers line up.
+pub(crate) struct Liveness {
+    /// Register components read before the chain writes them.
+    pub live_in: RegisterComponents,
+    /// Register components written by the chain that are read after it,
+    /// including every output component it writes.
+    pub live_out: RegisterComponents,
+    /// Index into `live_in` and component of every read of a live-in
+    /// register in the chain, in order.
+    pub in_reads: Vec<(usize, u32)>,
+    /// Last writer of every live-out component, relative to the start of the
+    /// chain.
+    pub out_writers: Vec<usize>,
+    /// Number of temporary registers the chain uses.
+    pub temp_count: u32,
+}
+
+/// Collect accesses into [`RegisterComponents`], in the order registers are
+/// first accessed.
+fn get_register_components<'a>(accesses: impl Iterator<Item = &'a Access>) -> RegisterComponents {
+    let mut registers: Vec<(RegisterFile, u32, BTreeSet<u32>)> = Vec::new();
+    for access in accesses {
+        let register = (access.file, access.register);
+        match registers.iter_mut().find(|(f, r, _)| (*f, *r) == register) {
+            Some((_, _, components)) => {
+                components.insert(access.component);
+            }
+            None => {
+                let components = BTreeSet::from([access.component]);
+                registers.push((access.file, access.register, components));
+            }
+        }
+    }
+    registers
+        .into_iter()
+        .map(|(file, register, components)| (file, register, components.into_iter().collect()))
+        .collect()
+}
+
+/// Get the index of the register a component is in.
+fn get_register_index(registers: &RegisterComponents, access: &Access) -> usize {
+    // Panic safety: the registers were collected from the accesses
+    registers
+        .iter()
+        .position(|(f, r, _)| (*f, *r) == (access.file, access.register))
+        .unwrap()
+}
+
+/// Whether a function can read or write an operand the way the entry point
+/// does, which rules out dynamic indices and registers only the entry point
+/// has, like indexable temporaries.
+fn is_liftable(op: &OperandToken0) -> bool {
+    let static_indices = op
+        .get_immediates()
+        .iter()
+        .all(|imm| matches!(imm, Immediate::U32(_) | Immediate::U64(_)));
+    let liftable_type = matches!(
+        op.get_operand_type(),
+        OperandType::Temp
+            | OperandType::Input
+            | OperandType::Output
+            | OperandType::ConstantBuffer
+            | OperandType::ImmediateConstantBuffer
+            | OperandType::Immediate32
+            | OperandType::Immediate64
+            | OperandType::Null
+    );
+    static_indices && liftable_type
+}
+
+/// Instructions of a shader that can be part of a chain, along with the data
+/// flow between them.
+#[derive(Default)]
+struct InstructionStream {
+    instructions: Vec<Instruction>,
+    /// Sources of each instruction, with absolute instruction indices.
+    sources: Vec<Vec<Source>>,
+    /// Whether each instruction could be lowered into a function.
+    liftable: Vec<bool>,
+    /// Register components read by all instructions, in order.
+    reads: Vec<Access>,
+    /// Register components written by all instructions, in order.
+    writes: Vec<Access>,
+    /// Positions of instructions outside chains that code can't be moved
+    /// across, like `retc`.
+    barriers: Vec<usize>,
+    /// Positions of instructions dxbc doesn't decode, which might read or
+    /// write any temporary.
+    unknowns: Vec<usize>,
+    /// Positions of `if`, `else`, `endif`, `loop`, `endloop` and `break`s,
+    /// past which the last writer of a register isn't necessarily the one
+    /// a read gets.
+    branches: Vec<usize>,
+    /// Well-known globals by constant buffer ID and register, along
+    /// with the register relative to the global's first register.
+    globals: FastHashMap<(u32, u32), (&'static str, u32)>,
+    /// Index of the instruction that last wrote each temporary register
+    /// component.
+    writers: FastHashMap<(u32, u32), usize>,
+}
+
+impl InstructionStream {
+    /// Find the registers of well-known globals in constant buffers.
+    fn register_globals(&mut self, chunk: &RdefChunk) {
+        for cb in &chunk.constant_buffers {
+            // Texture buffers share the chunk but don't have a CBuffer
+            // binding, and Unity's globals are never in one
+            let id = match chunk
+                .resource_bindings
+                .iter()
+                .find(|b| matches!(b.input_type, ShaderInputType::CBuffer) && b.name == cb.name)
+            {
+                Some(binding) => binding.id,
+                None => continue,
+            };
+            for var in &cb.variables {
+                let name = match UNITY_GLOBALS.iter().find(|name| **name == var.name) {
+                    Some(name) => *name,
+                    None => continue,
+                };
+                let first = var.offset / 16;
+                for r in 0..(var.size + 15) / 16 {
+                    self.globals.insert((id, first + r), (name, r));
+                }
+            }
+        }
+    }
+
+    /// Add an instruction if it can be part of a chain, returning whether it
+    /// was added.
+    fn push(&mut self, instruction: SparseInstruction) -> bool {
+        let chain_instruction = Instruction::try_from(&instruction);
+        let in_chain = chain_instruction.is_some();
+        let (dsts, srcs) = get_operands(&instruction.operands);
+        let unknown = matches!(instruction.operands, Operands::Unknown(_));
+        let index = self.instructions.len();
+
+        // Sources are read before destinations are written, e.g. for
+        // `mad r0, r1, r2, r0`
+        let sources = srcs.iter().map(|op| self.get_source(op)).collect();
+        for op in srcs.iter().chain(&dsts) {
+            // Dynamic indices read registers too, e.g. `r0` in `cb0[r0.x]`
+            for imm in &op.get_immediates() {
+                if let Immediate::Relative(op)
+                | Immediate::U32Relative(_, op)
+                | Immediate::U64Relative(_, op) = imm
+                {
+                    self.record_reads(op, index, in_chain);
+                }
+            }
+        }
+        for op in &srcs {
+            self.record_reads(op, index, in_chain);
+        }
+        for op in &dsts {
+            let file = match RegisterFile::from_operand(op) {
+                Some(file) if is_liftable(op) => file,
+                _ => continue,
+            };
+            let register = get_first_immediate(**op);
+            for c in get_selected_components(op) {
+                self.writes.push(Access {
+                    position: index,
+                    in_chain,
+                    file,
+                    register,
+                    component: c,
+                    writer: None,
+                });
+                if let RegisterFile::Temp = file {
+                    self.writers.insert((register, c), index);
+                }
+            }
+        }
+
+        // Instructions dxbc doesn't decode could have written any temporary,
+        // so nothing before them is known to reach later reads
+        if unknown {
+            self.writers.clear();
+            self.unknowns.push(index);
+        }
+
+        match chain_instruction {
+            Some(i) => {
+                // Blocks can't be split between functions
+                let control_flow = matches!(
+                    i,
+                    Instruction::If
+                        | Instruction::Else
+                        | Instruction::EndIf
+                        | Instruction::Loop
+                        | Instruction::EndLoop
+                        | Instruction::Break
+                        | Instruction::BreakC
+                );
+                if control_flow {
+                    self.branches.push(index);
+                }
+                let liftable =
+                    !control_flow && !unknown && srcs.iter().chain(&dsts).all(|op| is_liftable(op));
+                self.instructions.push(i);
+                self.sources.push(sources);
+                self.liftable.push(liftable);
+                true
+            }
+            None => {
+                if !is_declaration(&instruction.operands) {
+                    self.barriers.push(index);
+                }
+                false
+            }
+        }
+    }
+
+    /// Record the register components a source operand reads.
+    fn record_reads(&mut self, op: &OperandToken0, position: usize, in_chain: bool) {
+        let file = match RegisterFile::from_operand(op) {
+            Some(file) if is_liftable(op) => file,
+            _ => return,
+        };
+        let register = get_first_immediate(*op);
+        for c in get_selected_components(op) {
+            let writer = match file {
+                RegisterFile::Temp => self.writers.get(&(register, c)).copied(),
+                _ => None,
+            };
+            self.reads.push(Access {
+                position,
+                in_chain,
+                file,
+                register,
+                component: c,
+                writer,
+            });
+        }
+    }
+
+    /// Get the [`Liveness`] of a range of instructions, or [`None`] if it
+    /// can't be lowered into a function.
+    fn get_liveness(&self, range: Range<usize>) -> Option<Liveness> {
+        let liftable = self.liftable[range.clone()].iter().all(|l| *l);
+        let barrier = self
+            .barriers
+            .iter()
+            .any(|p| range.start < *p && *p < range.end);
+        if !liftable || barrier {
+            return None;
+        }
+
+        let inside = |a: &&Access| a.in_chain && range.contains(&a.position);
+        let written_inside = |a: &Access| a.writer.map_or(false, |w| range.contains(&w));
+        let in_reads: Vec<&Access> = self
+            .reads
+            .iter()
+            .filter(inside)
+            .filter(|a| !written_inside(a))
+            .collect();
+        let read_after: BTreeSet<(u32, u32)> = self
+            .reads
+            .iter()
+            .filter(|a| a.position >= range.end && written_inside(a))
+            .map(|a| (a.register, a.component))
+            .collect();
+        // Instructions dxbc doesn't decode after the chain might read any
+        // temporary it writes. Branches after it, including the ends of the
+        // blocks it's in, can take it to reads past other writers or back
+        // to reads before it
+        let clobbered = self
+            .unknowns
+            .iter()
+            .chain(&self.branches)
+            .any(|p| *p >= range.end);
+        let written: Vec<&Access> = self
+            .writes
+            .iter()
+            .filter(inside)
+            .filter(|a| {
+                a.file == RegisterFile::Output
+                    || clobbered
+                    || read_after.contains(&(a.register, a.component))
+            })
+            .collect();
+        let temp_count = self
+            .reads
+            .iter()
+            .chain(&self.writes)
+            .filter(inside)
+            .filter(|a| a.file == RegisterFile::Temp)
+            .map(|a| a.register + 1)
+            .max()
+            .unwrap_or(0);
+
+        let live_in = get_register_components(in_reads.iter().copied());
+        let live_out = get_register_components(written.iter().copied());
+        let in_reads = in_reads
+            .iter()
+            .map(|a| (get_register_index(&live_in, a), a.component))
+            .collect();
+        let mut out_writers = Vec::new();
+        for (file, register, components) in &live_out {
+            for c in components {
+                // Panic safety: live-out components are written in the chain
+                let writer = written
+                    .iter()
+                    .rev()
+                    .find(|a| (a.file, a.register, a.component) == (*file, *register, *c))
+                    .unwrap();
+                out_writers.push(writer.position - range.start);
+            }
+        }
+
+        Some(Liveness {
+            live_in,
+            live_out,
+            in_reads,
+            out_writers,
+            temp_count,
+        })
+    }
+
+    /// Get where a source operand gets its value from.
+    fn get_source(&self, op: &OperandToken0) -> Source {
+        match op.get_operand_type() {
+            OperandType::Temp => {
+                let register = get_first_immediate(*op);
+                let writers = get_selected_components(op)
+                    .into_iter()
+                    .map(|c| self.writers.get(&(register, c)).copied())
+                    .collect();
+                Source::Temp(writers)
+            }
+            // Dynamically indexed globals are arguments as far as chains
+            // are concerned
+            OperandType::ConstantBuffer => {
+                match (op.get_immediate(0), get_constant_buffer_slot(op)) {
+                    (Immediate::U32(id), Immediate::U32(register)) => {
+                        match self.globals.get(&(id, register)) {
+                            Some(&(name, r)) => Source::Global(name, r),
+                            None => Source::External,
+                        }
+                    }
+                    _ => Source::External,
+                }
+            }
+            _ => Source::External,
+        }
+    }
+
+    /// Get the [`Fingerprint`] of a range of instructions. Temporary
+    /// registers written before the range are external to it.
+    fn get_fingerprint(&self, range: Range<usize>) -> Fingerprint {
+        let start = range.start;
+        self.sources[range]
+            .iter()
+            .map(|sources| {
+                sources
+                    .iter()
+                    .map(|source| match source {
+                        Source::Temp(writers) => {
+                            let writers: Vec<Option<usize>> = writers
+                                .iter()
+                                .map(|w| w.filter(|w| *w >= start).map(|w| w - start))
+                                .collect();
+                            if writers.iter().all(Option::is_none) {
+                                Source::External
+                            } else {
+                                Source::Temp(writers)
+                            }
+                        }
+                        source => source.clone(),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Map of instructions to the macros that compile to them, by the
+/// [`Fingerprint`] of their data flow.
+pub type MacroMap = FastHashMap<Vec<Instruction>, FastHashMap<Fingerprint, Vec<String>>>;
 
 /// [`dxbc`] [`Consumer`] to create chains of instructions from macros compiled as shaders.
 pub struct CollectMacrosConsumer {
     macros: Vec<String>,
     current_macro: usize,
     map: MacroMap,
-    instructions: Vec<Instruction>,
+    stream: InstructionStream,
 }
 
 impl CollectMacrosConsumer {
@@ -150,31 +776,45 @@ impl CollectMacrosConsumer {
             macros,
             current_macro: 0,
             map: FastHashMap::default(),
-            instructions: Vec::new(),
+            stream: InstructionStream::default(),
         }
     }
 }
 
 impl Consumer for CollectMacrosConsumer {
     fn initialize(&mut self) -> Action {
-        self.instructions = Vec::new();
+        self.stream = InstructionStream::default();
+
+        Action::Continue
+    }
+
+    fn consume_rdef(&mut self, rdef: &RdefChunk) -> Action {
+        self.stream.register_globals(rdef);
 
         Action::Continue
     }
 
     fn consume_instruction(&mut self, _offset: u32, instruction: SparseInstruction) -> Action {
-        if let Some(i) = Instruction::try_from(instruction) {
-            self.instructions.push(i);
-        }
+        self.stream.push(instruction);
 
         Action::Continue
     }
 
     fn finalize(&mut self) -> Action {
-        self.map
-            .entry(self.instructions.clone())
+        let name = &self.macros[self.current_macro];
+        let fingerprint = self
+            .stream
+            .get_fingerprint(0..self.stream.instructions.len());
+        let names = self
+            .map
+            .entry(self.stream.instructions.clone())
             .or_default()
-            .push(self.macros[self.current_macro].clone());
+            .entry(fingerprint)
+            .or_default();
+        // Every profile a macro is compiled with can produce the same chain
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
         self.current_macro += 1;
 
         Action::Continue
@@ -194,11 +834,8 @@ fn get_instruction_chains() -> MacroMap {
     let macro_names = macros
         .iter()
         .map(|file| {
-            file.path()
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .into_owned()
+            let stem = file.path().file_stem().unwrap().to_string_lossy();
+            get_macro_name(&stem).to_owned()
         })
         .collect();
     let mut consumer = CollectMacrosConsumer::new(macro_names);
@@ -211,12 +848,175 @@ fn get_instruction_chains() -> MacroMap {
     consumer.map
 }
 
+/// Strip the profile from a compiled macro's file stem, e.g.
+/// `UnityObjectToClipPos_vs_5_0` to `UnityObjectToClipPos`.
+fn get_macro_name(stem: &str) -> &str {
+    stem.rsplitn(4, '_').last().unwrap_or(stem)
+}
+
+/// Node of an [`Automaton`].
+#[derive(Default)]
+struct Node {
+    /// Node for each instruction that continues a chain.
+    next: FastHashMap<Instruction, usize>,
+    /// Node of the longest proper suffix that's also the start of a chain.
+    fail: usize,
+    /// Chains ending at this node, longest first, as lengths and indices of
+    /// their values.
+    outputs: Vec<(usize, usize)>,
+}
+
+/// Aho–Corasick automaton that finds every chain of a map like [`MacroMap`]
+/// in a stream of instructions in one pass.
+struct Automaton<T> {
+    nodes: Vec<Node>,
+    values: Vec<T>,
+}
+
+impl<T: Clone> Automaton<T> {
+    fn new(map: &FastHashMap<Vec<Instruction>, T>) -> Self {
+        let mut nodes = vec![Node::default()];
+        let mut values = Vec::new();
+        for (chain, value) in map {
+            if chain.is_empty() {
+                continue;
+            }
+
+            let mut node = 0;
+            for instruction in chain {
+                node = match nodes[node].next.get(instruction) {
+                    Some(&next) => next,
+                    None => {
+                        let next = nodes.len();
+                        nodes.push(Node::default());
+                        nodes[node].next.insert(instruction.clone(), next);
+                        next
+                    }
+                };
+            }
+            nodes[node].outputs.push((chain.len(), values.len()));
+            values.push(value.clone());
+        }
+
+        // Breadth-first, so the nodes fail links point to are already done
+        let mut queue: VecDeque<usize> = nodes[0].next.values().copied().collect();
+        while let Some(node) = queue.pop_front() {
+            let children: Vec<(Instruction, usize)> = nodes[node]
+                .next
+                .iter()
+                .map(|(instruction, &child)| (instruction.clone(), child))
+                .collect();
+            for (instruction, child) in children {
+                let fail = Self::step_nodes(&nodes, nodes[node].fail, &instruction);
+                nodes[child].fail = fail;
+                let outputs = nodes[fail].outputs.clone();
+                nodes[child].outputs.extend(outputs);
+                queue.push_back(child);
+            }
+        }
+
+        Self { nodes, values }
+    }
+
+    /// Follow fail links from `node` until `instruction` continues a chain.
+    fn step_nodes(nodes: &[Node], mut node: usize, instruction: &Instruction) -> usize {
+        loop {
+            if let Some(&next) = nodes[node].next.get(instruction) {
+                return next;
+            }
+            if node == 0 {
+                return 0;
+            }
+            node = nodes[node].fail;
+        }
+    }
+
+    /// Find every chain in `instructions` as values and ranges, ordered by
+    /// the end and then the start of their ranges.
+    fn find<'a>(
+        &'a self,
+        instructions: &'a [Instruction],
+    ) -> impl Iterator<Item = (&'a T, Range<usize>)> + 'a {
+        instructions
+            .iter()
+            .scan(0, move |node, instruction| {
+                *node = Self::step_nodes(&self.nodes, *node, instruction);
+                Some(*node)
+            })
+            .enumerate()
+            .flat_map(move |(i, node)| {
+                self.nodes[node]
+                    .outputs
+                    .iter()
+                    .map(move |&(len, value)| (&self.values[value], i + 1 - len..i + 1))
+            })
+    }
+}
+
+/// Resolve overlapping matches, ordered by the end of their ranges, to the
+/// non-overlapping ones that cover the most instructions. Ties go to fewer,
+/// longer matches.
+fn get_cover(matches: &[MacroMatch], len: usize) -> Vec<MacroMatch> {
+    // Best score for the first `end` instructions, and the match ending there
+    // that achieves it, if any
+    let mut best = vec![(0, Reverse(0)); len + 1];
+    let mut choices = vec![None; len + 1];
+    let mut m = 0;
+    for end in 1..len + 1 {
+        best[end] = best[end - 1];
+        while m < matches.len() && matches[m].instruction_range.end == end {
+            let range = &matches[m].instruction_range;
+            let (covered, Reverse(count)) = best[range.start];
+            let score = (covered + range.len(), Reverse(count + 1));
+            if score > best[end] {
+                best[end] = score;
+                choices[end] = Some(m);
+            }
+            m += 1;
+        }
+    }
+
+    let mut cover = Vec::new();
+    let mut end = len;
+    while end > 0 {
+        match choices[end] {
+            Some(m) => {
+                cover.push(matches[m].clone());
+                end = matches[m].instruction_range.start;
+            }
+            None => end -= 1,
+        }
+    }
+    cover.reverse();
+    cover
+}
+
 static MACROS: Lazy<MacroMap> = Lazy::new(get_instruction_chains);
+static AUTOMATON: Lazy<Automaton<FastHashMap<Fingerprint, Vec<String>>>> =
+    Lazy::new(|| Automaton::new(&MACROS));
+
+/// Chain of instructions in a shader that matches one or more macros.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroMatch {
+    /// Names of the macros that compile to the chain's instructions and data
+    /// flow, e.g. `UnityObjectToClipPos`.
+    pub macro_names: Vec<String>,
+    /// Range of the chain in the instructions that can be part of one, which
+    /// excludes declarations and returns.
+    pub instruction_range: Range<usize>,
+    /// Range of the chain in the shader's bytecode, from the offset of its
+    /// first instruction to the end of its last.
+    pub byte_span: Range<u32>,
+}
 
 /// Collect all instructions from a shader to match macros.
 #[derive(Default)]
 pub struct MatchMacrosConsumer {
-    instructions: Vec<Instruction>,
+    stream: InstructionStream,
+    /// Bytecode ranges of the instructions.
+    spans: Vec<Range<u32>>,
+    matches: Vec<MacroMatch>,
+    cover: Vec<MacroMatch>,
 }
 
 impl MatchMacrosConsumer {
@@ -224,41 +1024,386 @@ impl MatchMacrosConsumer {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Macro matches found in the last parsed shader, ordered by the end and
+    /// then the start of their instruction ranges.
+    pub fn matches(&self) -> &[MacroMatch] {
+        &self.matches
+    }
+
+    /// Non-overlapping macro matches found in the last parsed shader that
+    /// cover the most instructions, ordered by their instruction ranges.
+    pub fn cover(&self) -> &[MacroMatch] {
+        &self.cover
+    }
+
+    /// Take the non-overlapping macro matches found in the last parsed shader.
+    pub fn into_cover(self) -> Vec<MacroMatch> {
+        self.cover
+    }
 }
 
 impl Consumer for MatchMacrosConsumer {
     fn initialize(&mut self) -> Action {
-        self.instructions = Vec::new();
+        self.stream = InstructionStream::default();
+        self.spans = Vec::new();
+        self.matches = Vec::new();
+        self.cover = Vec::new();
 
         Action::Continue
     }
 
-    fn consume_instruction(&mut self, _offset: u32, instruction: SparseInstruction) -> Action {
-        if let Some(i) = Instruction::try_from(instruction) {
-            self.instructions.push(i);
+    fn consume_rdef(&mut self, rdef: &RdefChunk) -> Action {
+        self.stream.register_globals(rdef);
+
+        Action::Continue
+    }
+
+    fn consume_instruction(&mut self, offset: u32, instruction: SparseInstruction) -> Action {
+        let end = offset + instruction.opcode.get_instruction_length();
+        if self.stream.push(instruction) {
+            self.spans.push(offset..end);
         }
 
         Action::Continue
     }
 
     fn finalize(&mut self) -> Action {
-        for j in 1..self.instructions.len() + 1 {
-            for i in 0..j {
-                let instruction_sample = &self.instructions[i..j];
-                if let Some(macros) = MACROS.get(instruction_sample) {
-                    dbg!(instruction_sample, macros);
-                }
-            }
-        }
+        // Opcodes find candidates, data flow confirms them
+        let stream = &self.stream;
+        let spans = &self.spans;
+        self.matches = AUTOMATON
+            .find(&stream.instructions)
+            .filter_map(|(macros, range)| {
+                let macro_names = macros.get(&stream.get_fingerprint(range.clone()))?;
+                Some(MacroMatch {
+                    macro_names: macro_names.clone(),
+                    byte_span: spans[range.start].start..spans[range.end - 1].end,
+                    instruction_range: range,
+                })
+            })
+            .collect();
+        self.cover = get_cover(&self.matches, stream.instructions.len());
 
         Action::Continue
     }
 }
 
+/// Find non-overlapping chains of instructions in a shader that match
+/// macros, covering as many instructions as possible.
+pub fn find_macros<T: AsRef<[u8]>>(shader_bytes: T) -> Result<Vec<MacroMatch>, Error> {
+    let mut consumer = MatchMacrosConsumer::new();
+    let mut parser = Parser::new(shader_bytes.as_ref(), &mut consumer);
+    parser.parse().map_err(Error::Parse)?;
+
+    Ok(consumer.into_cover())
+}
+
+/// Matched macro to lower into its own function.
+pub(crate) struct MacroRegion {
+    /// Name of the macro, and the function.
+    pub name: String,
+    /// Range of the chain in the instructions that can be part of one.
+    pub instruction_range: Range<usize>,
+    /// Registers passed to and returned from the function.
+    pub liveness: Liveness,
+}
+
+/// Find the macros in a shader that can be lowered into functions, ordered
+/// by their instruction ranges.
+///
+/// Macros that don't write anything read after them are left inline, since
+/// calling them would do nothing.
+pub(crate) fn get_macro_regions(shader_bytes: &[u8]) -> Result<Vec<MacroRegion>, Error> {
+    let mut consumer = MatchMacrosConsumer::new();
+    let mut parser = Parser::new(shader_bytes, &mut consumer);
+    parser.parse().map_err(Error::Parse)?;
+
+    let regions = consumer
+        .cover
+        .iter()
+        .filter_map(|m| {
+            let liveness = consumer.stream.get_liveness(m.instruction_range.clone())?;
+            (!liveness.live_out.is_empty()).then(|| MacroRegion {
+                name: m.macro_names[0].clone(),
+                instruction_range: m.instruction_range.clone(),
+                liveness,
+            })
+        })
+        .collect();
+    Ok(regions)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_macro_name() {
+        assert_eq!(
+            get_macro_name("UnityObjectToClipPos_vs_5_0"),
+            "UnityObjectToClipPos"
+        );
+        assert_eq!(get_macro_name("Decode_HDR_ps_4_1"), "Decode_HDR");
+    }
+
+    fn get_match(names: &[String], range: Range<usize>) -> MacroMatch {
+        MacroMatch {
+            macro_names: names.to_vec(),
+            byte_span: range.start as u32..range.end as u32,
+            instruction_range: range,
+        }
+    }
+
+    #[test]
+    fn test_automaton() {
+        let mut map: FastHashMap<Vec<Instruction>, Vec<String>> = FastHashMap::default();
+        map.insert(
+            vec![Instruction::Mul, Instruction::Add],
+            vec!["A".to_owned()],
+        );
+        map.insert(vec![Instruction::Add], vec!["B".to_owned()]);
+        map.insert(
+            vec![Instruction::Add, Instruction::Add, Instruction::Mul],
+            vec!["C".to_owned()],
+        );
+        let automaton = Automaton::new(&map);
+
+        let instructions = [
+            Instruction::Mul,
+            Instruction::Add,
+            Instruction::Add,
+            Instruction::Mul,
+            Instruction::Add,
+        ];
+        let found: Vec<(&Vec<String>, Range<usize>)> = automaton.find(&instructions).collect();
+        let expected: Vec<(&Vec<String>, Range<usize>)> = vec![
+            (&map[&vec![Instruction::Mul, Instruction::Add]], 0..2),
+            (&map[&vec![Instruction::Add]], 1..2),
+            (&map[&vec![Instruction::Add]], 2..3),
+            (
+                &map[&vec![Instruction::Add, Instruction::Add, Instruction::Mul]],
+                1..4,
+            ),
+            (&map[&vec![Instruction::Mul, Instruction::Add]], 3..5),
+            (&map[&vec![Instruction::Add]], 4..5),
+        ];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_cover() {
+        let a = ["A".to_owned()];
+        let b = ["B".to_owned()];
+        let c = ["C".to_owned()];
+        let matches = [
+            get_match(&a, 0..2),
+            get_match(&b, 1..2),
+            get_match(&b, 2..3),
+            get_match(&c, 1..4),
+            get_match(&a, 3..5),
+            get_match(&b, 4..5),
+        ];
+        let cover = get_cover(&matches, 5);
+        assert_eq!(
+            cover,
+            [
+                get_match(&a, 0..2),
+                get_match(&b, 2..3),
+                get_match(&a, 3..5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // mul r0.xy, v0.xy, cb0[1].xy
+        // add r1.x, r0.x, r2.x
+        // mad r0.x, r1.x, r0.y, r3.x
+        let stream = InstructionStream {
+            instructions: vec![Instruction::Mul, Instruction::Add, Instruction::Mad],
+            sources: vec![
+                vec![Source::External, Source::Global("unity_MatrixVP", 1)],
+                vec![Source::Temp(vec![Some(0)]), Source::Temp(vec![None])],
+                vec![
+                    Source::Temp(vec![Some(1)]),
+                    Source::Temp(vec![Some(0)]),
+                    Source::External,
+                ],
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            stream.get_fingerprint(1..3),
+            [
+                vec![Source::External, Source::External],
+                vec![
+                    Source::Temp(vec![Some(0)]),
+                    Source::External,
+                    Source::External,
+                ],
+            ]
+        );
+        assert_eq!(
+            stream.get_fingerprint(0..3)[2],
+            [
+                Source::Temp(vec![Some(1)]),
+                Source::Temp(vec![Some(0)]),
+                Source::External,
+            ]
+        );
+    }
+
+    fn access(
+        position: usize,
+        file: RegisterFile,
+        register: u32,
+        component: u32,
+        writer: Option<usize>,
+    ) -> Access {
+        Access {
+            position,
+            in_chain: true,
+            file,
+            register,
+            component,
+            writer,
+        }
+    }
+
+    #[test]
+    fn test_liveness() {
+        // mov r0.x, v1.x
+        // mul r1.xy, r0.xx, v0.yy
+        // add o0.x, r1.x, r2.x
+        // mov o1.x, r1.y
+        let stream = InstructionStream {
+            liftable: vec![true; 4],
+            reads: vec![
+                access(0, RegisterFile::Input, 1, 0, None),
+                access(1, RegisterFile::Temp, 0, 0, Some(0)),
+                access(1, RegisterFile::Input, 0, 1, None),
+                access(2, RegisterFile::Temp, 1, 0, Some(1)),
+                access(2, RegisterFile::Temp, 2, 0, None),
+                access(3, RegisterFile::Temp, 1, 1, Some(1)),
+            ],
+            writes: vec![
+                access(0, RegisterFile::Temp, 0, 0, None),
+                access(1, RegisterFile::Temp, 1, 0, None),
+                access(1, RegisterFile::Temp, 1, 1, None),
+                access(2, RegisterFile::Output, 0, 0, None),
+                access(3, RegisterFile::Output, 1, 0, None),
+            ],
+            ..Default::default()
+        };
+
+        // Registers written before the chain are live-in, and registers read
+        // after it and outputs are live-out, in the order they're accessed
+        let liveness = stream.get_liveness(1..3).unwrap();
+        assert_eq!(
+            liveness.live_in,
+            [
+                (RegisterFile::Temp, 0, vec![0]),
+                (RegisterFile::Input, 0, vec![1]),
+                (RegisterFile::Temp, 2, vec![0]),
+            ]
+        );
+        assert_eq!(liveness.in_reads, [(0, 0), (1, 1), (2, 0)]);
+        assert_eq!(
+            liveness.live_out,
+            [
+                (RegisterFile::Temp, 1, vec![1]),
+                (RegisterFile::Output, 0, vec![0]),
+            ]
+        );
+        assert_eq!(liveness.out_writers, [0, 1]);
+        assert_eq!(liveness.temp_count, 3);
+
+        // Code can't be moved across a barrier inside the chain, but can be
+        // up to one right after it
+        let barrier = InstructionStream {
+            barriers: vec![2],
+            ..stream
+        };
+        assert!(barrier.get_liveness(1..3).is_none());
+        assert!(barrier.get_liveness(0..2).is_some());
+
+        let unliftable = InstructionStream {
+            liftable: vec![true, false, true, true],
+            ..barrier
+        };
+        assert!(unliftable.get_liveness(2..4).is_some());
+        assert!(unliftable.get_liveness(0..2).is_none());
+    }
+
+    #[test]
+    fn test_liveness_with_branches() {
+        // if_nz v0.x
+        //   mov r0.x, v1.x
+        //   mul r1.x, r0.x, v1.y
+        // else
+        //   mov r1.x, v1.z
+        // endif
+        // mov o0.x, r1.x
+        let if_else = InstructionStream {
+            liftable: vec![false, true, true, false, true, false, true],
+            reads: vec![
+                access(0, RegisterFile::Input, 0, 0, None),
+                access(1, RegisterFile::Input, 1, 0, None),
+                access(2, RegisterFile::Temp, 0, 0, Some(1)),
+                access(2, RegisterFile::Input, 1, 1, None),
+                access(4, RegisterFile::Input, 1, 2, None),
+                access(6, RegisterFile::Temp, 1, 0, Some(4)),
+            ],
+            writes: vec![
+                access(1, RegisterFile::Temp, 0, 0, None),
+                access(2, RegisterFile::Temp, 1, 0, None),
+                access(4, RegisterFile::Temp, 1, 0, None),
+                access(6, RegisterFile::Output, 0, 0, None),
+            ],
+            branches: vec![0, 3, 5],
+            ..Default::default()
+        };
+
+        // The read after the block only looks written by the `else` branch
+        let liveness = if_else.get_liveness(1..3).unwrap();
+        assert_eq!(
+            liveness.live_out,
+            [
+                (RegisterFile::Temp, 0, vec![0]),
+                (RegisterFile::Temp, 1, vec![0]),
+            ]
+        );
+
+        // loop
+        //   breakc_nz r0.x
+        //   mul r0.x, r0.x, v0.x
+        // endloop
+        let looped = InstructionStream {
+            liftable: vec![false, false, true, false],
+            reads: vec![
+                access(1, RegisterFile::Temp, 0, 0, None),
+                access(2, RegisterFile::Temp, 0, 0, None),
+                access(2, RegisterFile::Input, 0, 0, None),
+            ],
+            writes: vec![access(2, RegisterFile::Temp, 0, 0, None)],
+            branches: vec![0, 1, 3],
+            ..Default::default()
+        };
+
+        // The next iteration reads what the chain writes before it
+        let liveness = looped.get_liveness(2..3).unwrap();
+        assert_eq!(
+            liveness.live_in,
+            [
+                (RegisterFile::Temp, 0, vec![0]),
+                (RegisterFile::Input, 0, vec![0]),
+            ]
+        );
+        assert_eq!(liveness.live_out, [(RegisterFile::Temp, 0, vec![0])]);
+    }
+
     #[test]
     fn test_chains() {
         println!("{:#?}", get_instruction_chains());
@@ -1,6 +1,12 @@
-use dxbc::dr::shex::Immediate;
-use dxbc::dr::{ComponentName, ComponentSwizzle, OperandToken0, ShaderVariableType};
-use naga::{ScalarKind, ScalarValue, SwizzleComponent, VectorSize};
+use dxbc::dr::shex::{Immediate, OperandType};
+use dxbc::dr::{
+    ComponentMask, ComponentName, ComponentSelectMode, InterpolationMode, OperandIndexDimension,
+    OperandToken0, Operands, SemanticName, ShaderVariableType,
+};
+use naga::{
+    BuiltIn, Interpolation, Sampling, ScalarKind, ScalarValue, SwizzleComponent, TypeInner,
+    VectorSize,
+};
 
 /// Get `naga`'s [`VectorSize`] from scalar.
 pub(crate) fn get_vector_size(size: usize) -> VectorSize {
@@ -12,15 +18,18 @@ pub(crate) fn get_vector_size(size: usize) -> VectorSize {
     }
 }
 
-/// Get `naga`'s [`ScalarValue`] from `dxbc`'s [`Immediate`].
-pub(crate) fn get_scalar_value(imm: &Immediate) -> ScalarValue {
-    match imm {
-        Immediate::U32(n) => ScalarValue::Uint(*n as u64),
-        Immediate::U64(n) => ScalarValue::Uint(*n),
-        // TODO: find out what these are
-        Immediate::Relative(_) => todo!(),
-        Immediate::U32Relative(_, _) => todo!(),
-        Immediate::U64Relative(_, _) => todo!(),
+/// Get `naga`'s [`ScalarValue`] of a [`ScalarKind`] from the bits of `dxbc`'s
+/// [`Immediate`].
+pub(crate) fn get_scalar_value(imm: &Immediate, kind: ScalarKind) -> ScalarValue {
+    match (imm, kind) {
+        (Immediate::U32(n), ScalarKind::Float) => ScalarValue::Float(f32::from_bits(*n) as f64),
+        (Immediate::U32(n), ScalarKind::Sint) => ScalarValue::Sint(*n as i32 as i64),
+        (Immediate::U32(n), _) => ScalarValue::Uint(*n as u64),
+        (Immediate::U64(n), ScalarKind::Float) => ScalarValue::Float(f64::from_bits(*n)),
+        (Immediate::U64(n), ScalarKind::Sint) => ScalarValue::Sint(*n as i64),
+        (Immediate::U64(n), _) => ScalarValue::Uint(*n),
+        // Relative immediates only index registers, they're never values
+        _ => unreachable!(),
     }
 }
 
@@ -37,21 +46,8 @@ pub(crate) fn get_immediate_width(imm: &Immediate) -> u8 {
     match imm {
         Immediate::U32(_) => 4,
         Immediate::U64(_) => 8,
-        Immediate::Relative(_) => todo!(),
-        Immediate::U32Relative(_, _) => todo!(),
-        Immediate::U64Relative(_, _) => todo!(),
-    }
-}
-
-/// Get value of [Immediate].
-// TODO: figure out the rest of these values and if u64 is best
-pub(crate) fn get_immediate_value(imm: &Immediate) -> u64 {
-    match imm {
-        Immediate::U32(n) => *n as u64,
-        Immediate::U64(n) => *n,
-        Immediate::Relative(_) => todo!(),
-        Immediate::U32Relative(_, _) => todo!(),
-        Immediate::U64Relative(_, _) => todo!(),
+        // Relative immediates only index registers, they're never values
+        _ => unreachable!(),
     }
 }
 
@@ -66,26 +62,19 @@ pub(crate) fn get_first_immediate(op: OperandToken0) -> u32 {
     }
 }
 
-/// Get `naga`'s [`SwizzleComponent`] from `dxbc`'s [`ComponentName`].
-pub(crate) fn get_swizzle_component(c: ComponentName) -> SwizzleComponent {
-    match c {
-        ComponentName::X => SwizzleComponent::X,
-        ComponentName::Y => SwizzleComponent::Y,
-        ComponentName::Z => SwizzleComponent::Z,
-        ComponentName::W => SwizzleComponent::W,
+/// Get the immediate of a constant buffer operand holding the 16-byte slot
+/// it reads.
+///
+/// Shader model 5.1 operands have the buffer's range ID first and the
+/// register in its space second, e.g. `CB0[3][4]`, while earlier ones only
+/// have the register, e.g. `cb3[4]`.
+pub(crate) fn get_constant_buffer_slot(op: &OperandToken0) -> Immediate {
+    match op.get_index_dimension() {
+        OperandIndexDimension::_3D => op.get_immediate(2),
+        _ => op.get_immediate(1),
     }
 }
 
-/// Get `naga`'s swizzle representation from `dxbc`'s [`ComponentSwizzle`].
-pub(crate) fn get_swizzle_components(c: &ComponentSwizzle) -> [SwizzleComponent; 4] {
-    [
-        get_swizzle_component(c.0),
-        get_swizzle_component(c.1),
-        get_swizzle_component(c.2),
-        get_swizzle_component(c.3),
-    ]
-}
-
 /// Get [`ComponentName`] index.
 pub(crate) fn get_component_name_index(c: ComponentName) -> u32 {
     match c {
@@ -96,13 +85,62 @@ pub(crate) fn get_component_name_index(c: ComponentName) -> u32 {
     }
 }
 
-/// Get [`SwizzleComponent`] index.
-pub(crate) fn get_swizzle_component_index(c: &SwizzleComponent) -> u32 {
-    match c {
-        SwizzleComponent::X => 0,
-        SwizzleComponent::Y => 1,
-        SwizzleComponent::Z => 2,
-        SwizzleComponent::W => 3,
+/// Get indices of the components enabled in a [`ComponentMask`].
+pub(crate) fn get_mask_components(mask: ComponentMask) -> Vec<u32> {
+    [
+        ComponentMask::COMPONENT_MASK_R,
+        ComponentMask::COMPONENT_MASK_G,
+        ComponentMask::COMPONENT_MASK_B,
+        ComponentMask::COMPONENT_MASK_A,
+    ]
+    .iter()
+    .enumerate()
+    .filter(|(_, c)| mask.contains(**c))
+    .map(|(i, _)| i as u32)
+    .collect()
+}
+
+/// Get indices of the register components an operand selects, in order.
+pub(crate) fn get_selected_components(op: &OperandToken0) -> Vec<u32> {
+    match op.get_component_select_mode() {
+        ComponentSelectMode::Mask => get_mask_components(op.get_component_mask()),
+        ComponentSelectMode::Swizzle => {
+            let swizzle = op.get_component_swizzle();
+            vec![
+                get_component_name_index(swizzle.0),
+                get_component_name_index(swizzle.1),
+                get_component_name_index(swizzle.2),
+                get_component_name_index(swizzle.3),
+            ]
+        }
+        ComponentSelectMode::Select1 => {
+            vec![get_component_name_index(op.get_component_swizzle().0)]
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Get the doubles an operand selects, 0 for `.xy` and 1 for `.zw`.
+pub(crate) fn get_selected_doubles(op: &OperandToken0) -> Vec<u32> {
+    get_selected_components(op)
+        .chunks(2)
+        .map(|pair| pair[0] / 2)
+        .collect()
+}
+
+/// Whether an operand is a `null` destination whose result is thrown away.
+pub(crate) fn is_null(op: &OperandToken0) -> bool {
+    matches!(op.get_operand_type(), OperandType::Null)
+}
+
+/// Get [`SwizzleComponent`] from its index.
+pub(crate) fn get_swizzle_component_from_index(index: u32) -> SwizzleComponent {
+    match index {
+        0 => SwizzleComponent::X,
+        1 => SwizzleComponent::Y,
+        2 => SwizzleComponent::Z,
+        3 => SwizzleComponent::W,
+        _ => unreachable!(),
     }
 }
 
@@ -111,8 +149,148 @@ pub(crate) fn get_scalar_kind(ty: ShaderVariableType) -> ScalarKind {
     match ty {
         ShaderVariableType::Int_ => ScalarKind::Sint,
         ShaderVariableType::UInt => ScalarKind::Uint,
-        ShaderVariableType::Float => ScalarKind::Float,
+        ShaderVariableType::Float | ShaderVariableType::Double => ScalarKind::Float,
         ShaderVariableType::Bool => ScalarKind::Bool,
         _ => todo!(),
     }
 }
+
+/// Get byte width of `dxbc`'s [`ShaderVariableType`] as a `naga` scalar.
+pub(crate) fn get_variable_width(ty: ShaderVariableType) -> u8 {
+    match ty {
+        ShaderVariableType::Double => 8,
+        _ => get_scalar_width(get_scalar_kind(ty)),
+    }
+}
+
+/// Get `naga`'s [`BuiltIn`] from `dxbc`'s system-value [`SemanticName`].
+///
+/// Returns [`None`] if the semantic isn't a built-in in `naga`. Render
+/// targets aren't built-ins and have to be handled separately.
+pub(crate) fn get_builtin(semantic: SemanticName) -> Option<BuiltIn> {
+    match semantic {
+        SemanticName::Position => Some(BuiltIn::Position),
+        SemanticName::ClipDistance => Some(BuiltIn::ClipDistance),
+        SemanticName::CullDistance => Some(BuiltIn::CullDistance),
+        SemanticName::VertexId => Some(BuiltIn::VertexIndex),
+        SemanticName::PrimitiveId => Some(BuiltIn::PrimitiveIndex),
+        SemanticName::InstanceId => Some(BuiltIn::InstanceIndex),
+        SemanticName::IsFrontFace => Some(BuiltIn::FrontFacing),
+        SemanticName::SampleIndex => Some(BuiltIn::SampleIndex),
+        SemanticName::Depth | SemanticName::DepthGreaterEqual | SemanticName::DepthLessEqual => {
+            Some(BuiltIn::FragDepth)
+        }
+        SemanticName::Coverage => Some(BuiltIn::SampleMask),
+        // ViewIndex is the multiview index, not the viewport, and naga has no
+        // layer or viewport built-ins
+        SemanticName::RenderTargetArrayIndex | SemanticName::ViewportArrayIndex => None,
+        // Hull shaders aren't supported by naga
+        SemanticName::FinalQuadEdgeTessfactor
+        | SemanticName::FinalQuadInsideTessfactor
+        | SemanticName::FinalTriEdgeTessfactor
+        | SemanticName::FinalTriInsideTessfactor
+        | SemanticName::FinalLineDetailTessfactor
+        | SemanticName::FinalLineDensityTessfactor => None,
+        SemanticName::Undefined | SemanticName::Target => None,
+    }
+}
+
+/// Get the [`ScalarKind`] and component count of a scalar or vector
+/// [`TypeInner`].
+pub(crate) fn get_scalar_kind_and_size(inner: &TypeInner) -> (ScalarKind, usize) {
+    match *inner {
+        TypeInner::Scalar { kind, .. } => (kind, 1),
+        TypeInner::Vector { size, kind, .. } => (kind, size as usize),
+        TypeInner::ValuePointer { size, kind, .. } => (kind, size.map_or(1, |s| s as usize)),
+        _ => unimplemented!(),
+    }
+}
+
+/// Get `naga`'s [`Interpolation`] and [`Sampling`] from `dxbc`'s
+/// [`InterpolationMode`].
+///
+/// Returns [`None`] if the mode is undefined.
+pub(crate) fn get_interpolation(
+    mode: InterpolationMode,
+) -> Option<(Interpolation, Option<Sampling>)> {
+    match mode {
+        InterpolationMode::Undefined => None,
+        InterpolationMode::Constant => Some((Interpolation::Flat, None)),
+        InterpolationMode::Linear => Some((Interpolation::Perspective, Some(Sampling::Center))),
+        InterpolationMode::LinearCentroid => {
+            Some((Interpolation::Perspective, Some(Sampling::Centroid)))
+        }
+        InterpolationMode::LinearNoPerspective => {
+            Some((Interpolation::Linear, Some(Sampling::Center)))
+        }
+        InterpolationMode::LinearNoPerspectiveCentroid => {
+            Some((Interpolation::Linear, Some(Sampling::Centroid)))
+        }
+        InterpolationMode::LinearSample => {
+            Some((Interpolation::Perspective, Some(Sampling::Sample)))
+        }
+        InterpolationMode::LinearNoPerspectiveSample => {
+            Some((Interpolation::Linear, Some(Sampling::Sample)))
+        }
+    }
+}
+
+/// Whether [`Operands`] are a declaration, which all come before the first
+/// other instruction.
+pub(crate) fn is_declaration(operands: &Operands) -> bool {
+    matches!(
+        operands,
+        Operands::DclGlobalFlags(_)
+            | Operands::DclInput(_)
+            | Operands::DclInputPs(_)
+            | Operands::DclOutput(_)
+            | Operands::DclConstantBuffer(_)
+            | Operands::DclResource(_)
+            | Operands::DclSampler(_)
+            | Operands::DclOutputSiv(_)
+            | Operands::DclOutputSgv(_)
+            | Operands::DclInputPsSiv(_)
+            | Operands::DclInputPsSgv(_)
+            | Operands::DclTemps(_)
+            | Operands::DclIndexableTemp(_)
+            | Operands::DclImmediateConstantBuffer(_)
+    )
+}
+
+/// Get a struct member name for an HLSL semantic, e.g. `texcoord_3` for
+/// `TEXCOORD3`.
+///
+/// Characters that can't be in identifiers become underscores. The index
+/// always gets an underscore before it, so names ending in digits like
+/// `TEXCOORD1` can't run together with it.
+pub(crate) fn get_semantic_member_name(name: &str, index: u32) -> String {
+    let mut member: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    member.push('_');
+    member.push_str(&index.to_string());
+    member
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_semantic_member_names() {
+        assert_eq!(get_semantic_member_name("TEXCOORD", 3), "texcoord_3");
+        assert_eq!(get_semantic_member_name("SV_Target", 0), "sv_target_0");
+        assert_ne!(
+            get_semantic_member_name("TEXCOORD", 11),
+            get_semantic_member_name("TEXCOORD1", 1)
+        );
+        assert_eq!(get_semantic_member_name("COLOR1", 2), "color1_2");
+    }
+}
@@ -1,5 +1,8 @@
 use naga::back::hlsl;
-use naga_dx::parse;
+use naga::ResourceBinding;
+use naga_dx::{
+    parse, parse_pipeline, parse_shader as parse_shader_bytes, parse_with_options, Options,
+};
 use std::fs;
 use test_generator::test_resources;
 
@@ -13,9 +16,23 @@ fn parse_shader(shader_path: &str) {
     assert!(dxbc.is_ok(), "Couldn't parse shader!");
     let (module, info) = dxbc.unwrap();
 
+    // Bindings are register spaces and registers by default, so map them
+    // straight back
+    let binding_map = module
+        .global_variables
+        .iter()
+        .filter_map(|(_, var)| var.binding.clone())
+        .map(|binding| {
+            let target = hlsl::BindTarget {
+                space: binding.group as u8,
+                register: binding.binding,
+            };
+            (binding, target)
+        })
+        .collect();
     let hlsl_options = hlsl::Options {
         shader_model: hlsl::ShaderModel::V5_0,
-        binding_map: hlsl::BindingMap::default(),
+        binding_map,
         fake_missing_bindings: false,
         special_constants_binding: None,
     };
@@ -24,3 +41,100 @@ fn parse_shader(shader_path: &str) {
     hlsl_writer.write(&module, &info).unwrap();
     println!("{}", &hlsl_code);
 }
+
+#[test_resources("shaders/compiled/constant_buffer_*.dxbc")]
+fn bind_constant_buffers(shader_path: &str) {
+    let bytes = fs::read(shader_path).unwrap();
+    let (module, _) = parse(bytes).unwrap();
+
+    let buffer = module
+        .global_variables
+        .iter()
+        .find(|(_, var)| var.name.as_deref() == Some("Buffer_2"));
+    assert!(buffer.is_some(), "Constant buffer is missing!");
+    let (_, buffer) = buffer.unwrap();
+    assert_eq!(
+        buffer.binding,
+        Some(ResourceBinding {
+            group: 0,
+            binding: 2
+        }),
+        "Constant buffer isn't bound to its register!"
+    );
+}
+
+#[test_resources("shaders/compiled/**/*.dxbc")]
+fn validate_with_required_capabilities(shader_path: &str) {
+    let bytes = fs::read(shader_path).unwrap();
+    let shader = parse_shader_bytes(&bytes).unwrap();
+
+    let options = Options {
+        capabilities: Some(shader.capabilities),
+        ..Options::default()
+    };
+    assert!(
+        parse_with_options(&bytes, &options).is_ok(),
+        "Shader needs capabilities it doesn't report!"
+    );
+}
+
+#[test_resources("shaders/compiled/**/*.dxbc")]
+fn replace_macros(shader_path: &str) {
+    let bytes = fs::read(shader_path).unwrap();
+
+    let options = Options {
+        replace_macros: true,
+        ..Options::default()
+    };
+    assert!(
+        parse_with_options(&bytes, &options).is_ok(),
+        "Couldn't parse shader with macros replaced!"
+    );
+}
+
+#[test]
+fn replace_compiled_macro() {
+    let bytes = fs::read("shaders/compiled/macros/UnityWorldToClipPos_ps_5_0.dxbc").unwrap();
+
+    let options = Options {
+        replace_macros: true,
+        ..Options::default()
+    };
+    // Parsing validates the module, calls to lifted macros included
+    let shader = parse_with_options(&bytes, &options);
+    assert!(
+        shader.is_ok(),
+        "Couldn't parse shader with macros replaced!"
+    );
+    let module = shader.unwrap().module;
+    assert!(
+        module
+            .functions
+            .iter()
+            .any(|(_, f)| f.name.as_deref() == Some("UnityWorldToClipPos")),
+        "Macro wasn't lifted into a function!"
+    );
+}
+
+#[test_resources("shaders/compiled/*_vs_5_0.dxbc")]
+fn parse_pipeline_shaders(vs_path: &str) {
+    let mut shaders = vec![fs::read(vs_path).unwrap()];
+    // Pixel shaders compiled from the same source file make up the rest of the pipeline
+    if let Ok(bytes) = fs::read(vs_path.replace("_vs_", "_ps_")) {
+        shaders.push(bytes);
+    }
+
+    let pipeline = parse_pipeline(&shaders);
+    assert!(pipeline.is_ok(), "Couldn't parse pipeline!");
+    let pipeline = pipeline.unwrap();
+
+    if let [vs, ps] = &pipeline[..] {
+        for (location, semantic) in &ps.semantics.inputs {
+            assert_eq!(
+                vs.semantics.outputs.get(location),
+                Some(semantic),
+                "Varying locations don't match!"
+            );
+        }
+    }
+}
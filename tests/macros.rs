@@ -1,7 +1,8 @@
 use std::fs;
+use std::path::Path;
 
 use dxbc::binary::Parser;
-use naga_dx::MatchMacrosConsumer;
+use naga_dx::{find_macros, MatchMacrosConsumer};
 use test_generator::test_resources;
 
 #[test_resources("shaders/compiled/**/*.dxbc")]
@@ -14,4 +15,33 @@ fn get_macros(shader_path: &str) {
     let mut parser = Parser::new(bytes.as_ref(), &mut consumer);
     let result = parser.parse();
     assert!(result.is_ok(), "Couldn't match macros!");
+
+    let matches = find_macros(&bytes);
+    assert!(matches.is_ok(), "Couldn't find macros!");
+    assert_eq!(
+        consumer.cover(),
+        &matches.unwrap()[..],
+        "Consumer and find_macros disagree!"
+    );
+}
+
+#[test_resources("shaders/compiled/macros/*.dxbc")]
+fn find_compiled_macro(shader_path: &str) {
+    let bytes = fs::read(shader_path).unwrap();
+    let matches = find_macros(&bytes).unwrap();
+
+    // A macro compiled on its own is a chain of every instruction it has
+    let stem = Path::new(shader_path)
+        .file_stem()
+        .unwrap()
+        .to_string_lossy();
+    let name = stem.rsplitn(4, '_').last().unwrap();
+    if let Some(m) = matches.iter().max_by_key(|m| m.instruction_range.len()) {
+        assert!(
+            m.macro_names.iter().any(|n| n == name),
+            "{} isn't in its own chain!",
+            name
+        );
+        assert!(m.byte_span.start < m.byte_span.end, "Empty byte span!");
+    }
 }